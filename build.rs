@@ -0,0 +1,11 @@
+use vergen::EmitBuilder;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Embed build metadata so `--version` can identify the exact binary in bug reports
+    EmitBuilder::builder()
+        .build_timestamp()
+        .cargo_target_triple()
+        .git_sha(false)
+        .emit()?;
+    Ok(())
+}
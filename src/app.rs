@@ -1,111 +1,1598 @@
+use chrono::{DateTime, Utc};
+use futures::future::LocalBoxFuture;
 use glob::glob;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use rayon::prelude::*;
+use std::collections::{HashMap, HashSet};
 use std::env::{self, VarError};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
 use std::process::exit;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tempfile::NamedTempFile;
+use uuid::Uuid;
 
+use crate::archive;
+use crate::config::AppConfig;
 use crate::db::{self, Credentials};
+use crate::entity::TcoResolver;
+use crate::error::PipelineError;
+use crate::filter::HashtagFilter;
 use crate::json;
+use crate::notify::{CompletionNotifier, CompletionStatus, WebhookNotifier};
+use crate::progress::{Progress, ProgressReporter};
+use crate::quality::{self, ScoreDistribution};
+use crate::sink::{DataSink, Neo4jSink};
+use crate::stage::{Stage, StageId, StageResult, StageRunner};
+use crate::stats::{EntityStats, PipelineStats, TopN};
 
-pub struct App {
-    credentials: db::Credentials,
-    tweet_count: u64,
-    deletet_tweet_count: u32,
-    retweet_count: u32,
+// State handed from one stage of an import to the next
+#[derive(Default)]
+struct ImportState {
+    started_at: DateTime<Utc>,
+    // Tweets of each parsed file, taken by the insert stage
+    tweets: Vec<Vec<json::Tweet>>,
+    // Input file of each parsed file, for the archive names
+    sources: Vec<PathBuf>,
+    // Chunks of large input files, deleted once the tweets are inserted
+    split_files: Vec<NamedTempFile>,
+    skipped_files: usize,
+    skipped_large_files: usize,
+    files_processed: usize,
+    newest_tweet_at: Option<DateTime<Utc>>,
+    sample_seed: Option<u64>,
+    quality_scores: ScoreDistribution,
+    entities: EntityStats,
+    insert_stats: db::InsertStats,
+    relations_built: bool,
+    depths_set: bool,
+    reply_depth_histogram: Vec<(u32, u64)>,
 }
 
-impl App {
+// Everything the stages of an import work with, so that a stage can run on
+// its own in tests
+pub struct PipelineContext<S: DataSink> {
+    sink: S,
+    credentials: Arc<db::Credentials>,
+    config: AppConfig,
+    parse_options: json::ParseOptions,
+    parse_stats: json::ParseStats,
+    protected_tweet_count: u32,
+    notifier: Option<CompletionNotifier>,
+    webhook: Option<WebhookNotifier>,
+    run_id: Uuid,
+    state: ImportState,
+}
+
+pub struct App<S: DataSink> {
+    ctx: PipelineContext<S>,
+}
+
+impl<S: DataSink> App<S> {
     pub async fn run(&mut self) {
-        let res = db::prepare_database(self.credentials.clone()).await;
+        let ctx = &mut self.ctx;
+        if ctx.run_command().await {
+            return;
+        }
+        ctx.start_import().await;
+        let mut stages = StageRunner::new(&ctx.config.skip_stage, &ctx.config.only_stage);
+        let result = stages.run(&import_stages(&ctx.config), ctx).await;
+        stages.report();
+        if let Err(e) = result {
+            ctx.fail(ctx.state.insert_stats.tweets_inserted, &e.to_string())
+                .await
+        }
+        ctx.finish_import(stages.results()).await;
+    }
+}
 
-        match res {
-            Ok(_) => (),
-            Err(e) => {
+impl<S: DataSink> PipelineContext<S> {
+    // Runs the command of the one-shot options, returns false when there is
+    // none and the tweets should be imported
+    async fn run_command(&mut self) -> bool {
+        if let Some(query) = self.config.query.clone() {
+            self.run_query(&query).await;
+            return true;
+        }
+        if self.config.drop_schema {
+            if let Err(e) =
+                db::drop_all_pipeline_managed_constraints_and_indexes(self.credentials.clone())
+                    .await
+            {
                 eprintln!("{}", e);
-                eprintln!("Could not connect to the database. Check if it's running.");
+                eprintln!("Could not drop the schema.");
                 exit(1)
             }
+            println!("Done!");
+            return true;
+        }
+        if let Some(cypher) = self.config.neo4j_query.clone() {
+            self.run_cypher(&cypher).await;
+            return true;
+        }
+        if let Some(path) = self.config.backup.clone() {
+            match db::backup_to_cypher_dump(self.credentials.clone(), &path).await {
+                Ok(statements) => {
+                    println!("{} statements written to {}", statements, path.display())
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
+                    eprintln!("Could not back up the graph.");
+                    exit(1)
+                }
+            }
+            return true;
+        }
+        if self.config.show_schema {
+            self.show_schema().await;
+            return true;
+        }
+        if self.config.export_nodes {
+            self.export_nodes().await;
+            return true;
+        }
+        if let Some(label) = self.config.label_stats.clone() {
+            self.show_label_stats(&label).await;
+            return true;
+        }
+        if self.config.optimize_graph {
+            match db::optimize_graph(self.credentials.clone()).await {
+                Ok(report) => println!("{}", report),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    eprintln!("Could not read the graph statistics.");
+                    exit(1)
+                }
+            }
+            return true;
+        }
+        if let Some(path) = self.config.import_users_only.clone() {
+            self.import_users(&path).await;
+            return true;
+        }
+        if let Some(run_id) = self.config.purge_run.clone() {
+            match db::purge_import(self.credentials.clone(), &run_id).await {
+                Ok(report) => {
+                    println!("Tweets deleted: {}", report.tweets_deleted);
+                    println!("Relationships deleted: {}", report.relationships_deleted);
+                    println!(
+                        "Tweets retained as imported by another run: {}",
+                        report.tweets_retained
+                    );
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
+                    eprintln!("Could not purge the import run.");
+                    exit(1)
+                }
+            }
+            return true;
+        }
+        if self.config.migrate_hashtags {
+            match db::migrate_hashtag_array_to_nodes(self.credentials.clone()).await {
+                Ok(stats) => {
+                    println!(
+                        "Hashtag nodes: {} created, {} already existing",
+                        stats.hashtags_created, stats.hashtags_existing
+                    );
+                    println!("TAGGED_WITH relations added: {}", stats.relations_created);
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
+                    eprintln!("Could not migrate the hashtags.");
+                    exit(1)
+                }
+            }
+            if !self.config.user_similarity_graph {
+                return true;
+            }
         }
+        if self.config.user_similarity_graph {
+            eprintln!(
+                "Warning: the user similarity graph compares every pair of users of each hashtag, O(users²) on popular hashtags"
+            );
+            match db::create_user_similarity_graph(
+                self.credentials.clone(),
+                self.config.min_shared_hashtags,
+                self.config.similarity_top_k,
+            )
+            .await
+            {
+                Ok(relations) => println!("SIMILAR_TO relations: {}", relations),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    eprintln!("Could not create the user similarity graph.");
+                    exit(1)
+                }
+            }
+            return true;
+        }
+        false
+    }
 
-        // For the async function, we need to collect results and process them after parallel execution
-        let files: Vec<_> = glob("/data/airlines-*.json")
-            .expect("Failed to read glob pattern")
-            .filter_map(Result::ok)
-            .collect();
+    // Everything an import needs before its first stage
+    async fn start_import(&mut self) {
+        let started_at = Utc::now();
 
-        let results = self.parse_files(files);
+        if let Some(path) = &self.config.notify_socket {
+            match CompletionNotifier::bind(path) {
+                Ok(notifier) => self.notifier = Some(notifier),
+                Err(e) => eprintln!("Could not listen on {}: {}", path.display(), e),
+            }
+        }
 
-        // Process database insertions sequentially since they're async operations
-        for tweets in results {
-            db::insert_new_tweets(self.credentials.clone(), tweets).await;
+        println!("Import run {}", self.run_id);
+        if self.config.store_raw_json {
+            eprintln!(
+                "Warning: --store-raw-json keeps the original JSON of every tweet, \
+                which can make the database several times larger"
+            );
+        }
+        if let Some(run_id) = &self.config.since_run {
+            match db::newest_tweet_of_run(self.credentials.clone(), run_id).await {
+                Ok(since) => {
+                    if let Some(since) = since {
+                        println!("Importing tweets created after {}", since);
+                    }
+                    self.parse_options.since = since;
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
+                    eprintln!("Could not find the tweets of run {}.", run_id);
+                    self.fail(0, &e.to_string()).await
+                }
+            }
+        }
+
+        self.state = ImportState {
+            started_at,
+            newest_tweet_at: self.parse_options.since,
+            sample_seed: self.config.sample_rate.map(|_| {
+                self.config
+                    .sample_seed
+                    .unwrap_or_else(|| rand::rng().random())
+            }),
+            ..Default::default()
+        };
+    }
+
+    async fn prepare_db(&mut self) -> Result<(), PipelineError> {
+        let res = db::prepare_database(
+            self.credentials.clone(),
+            Duration::from_millis(self.config.constraint_wait_ms),
+        )
+        .await;
+
+        if let Err(e) = res {
+            eprintln!("{}", e);
+            eprintln!("Could not connect to the database. Check if it's running.");
+            return Err(e);
         }
+        Ok(())
+    }
+
+    async fn parse_input(&mut self) -> Result<(), PipelineError> {
+        self.state.tweets = if self.config.input == "-" {
+            self.state.sources.push(PathBuf::from("stdin"));
+            match self.parse_stdin() {
+                Ok(tweets) => vec![tweets],
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return Err(e);
+                }
+            }
+        } else {
+            // For the async function, we need to collect results and process them after parallel execution
+            let mut files: Vec<_> = glob(&self.config.input)
+                .expect("Failed to read glob pattern")
+                .filter_map(Result::ok)
+                .collect();
+            if files.is_empty() {
+                report_unmatched_pattern(&self.config.input);
+                return Err(PipelineError::Aborted(
+                    "no input file matches the input pattern".to_string(),
+                ));
+            }
+            let before = files.len();
+            files.retain(|file| looks_like_json(file));
+            self.state.skipped_files = before - files.len();
+            if let Some(max_size) = self.config.max_file_size
+                && self.config.split_threshold_lines.is_none()
+            {
+                let before = files.len();
+                files.retain(|file| within_size_limit(file, max_size));
+                self.state.skipped_large_files = before - files.len();
+            }
+            if files.is_empty() {
+                eprintln!("None of the input files looks like JSON or is small enough");
+                return Err(PipelineError::Aborted(
+                    "none of the input files looks like JSON or is small enough".to_string(),
+                ));
+            }
+            self.state.sources = files.clone();
+            if let Some(max_lines) = self.config.split_threshold_lines {
+                match split_large_files(files, max_lines) {
+                    Ok((paths, chunks)) => {
+                        (files, self.state.sources) = paths.into_iter().unzip();
+                        self.state.split_files = chunks;
+                    }
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        eprintln!("Could not split the input files.");
+                        return Err(e);
+                    }
+                }
+            }
+
+            if self.config.verify_utf8 && !self.verify_utf8(&files) {
+                eprintln!(
+                    "Invalid UTF-8 in the input, use --utf8-replace-invalid to import anyway"
+                );
+                return Err(PipelineError::Aborted(
+                    "invalid UTF-8 in the input".to_string(),
+                ));
+            }
 
-        println!("Number of tweets: {}", self.tweet_count);
-        println!("Number of deleted tweets: {}", self.deletet_tweet_count);
+            let estimated_memory = self.estimate_memory_usage(&files);
+            println!(
+                "Estimated peak memory usage: {} MB",
+                estimated_memory / 1024 / 1024
+            );
+            let memory_limit = self.config.memory_limit.or_else(|| {
+                sys_info::mem_info()
+                    .ok()
+                    .map(|mem| mem.avail * 1024 / 10 * 8)
+            });
+            if let Some(limit) = memory_limit
+                && estimated_memory > limit
+            {
+                eprintln!(
+                    "Warning: the import may need more than the {} MB of memory available",
+                    limit / 1024 / 1024
+                );
+            }
+
+            let parsed = match self.config.parser {
+                json::ParserExecutor::Rayon => self.parse_files(files),
+                json::ParserExecutor::Tokio => self.parse_files_tokio(files).await,
+            };
+            match parsed {
+                Ok(parsed) => self.collect_parse_results(parsed),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return Err(e);
+                }
+            }
+        };
+
+        if self.state.skipped_files > 0 {
+            println!("Files skipped as not JSON: {}", self.state.skipped_files);
+        }
+        if self.state.skipped_large_files > 0 {
+            println!(
+                "Files skipped as too large: {}",
+                self.state.skipped_large_files
+            );
+        }
+        println!("Number of tweets: {}", self.parse_stats.tweet_num);
+        println!("Number of deleted tweets: {}", self.parse_stats.deleted);
         println!(
             "Percentage of retweets: {}%",
-            self.retweet_count as f32 / self.tweet_count as f32 * 100.
+            self.parse_stats.retweet_num as f32 / self.parse_stats.tweet_num as f32 * 100.
+        );
+        println!(
+            "Intra-file duplicate tweets removed: {}",
+            self.parse_stats.duplicates_removed
+        );
+        println!(
+            "Lines that failed to parse: {}",
+            self.parse_stats.parse_errors
+        );
+        if self.config.on_error == json::ParseErrorStrategy::Retry {
+            println!(
+                "Lines repaired: {} of {}",
+                self.parse_stats.repaired_lines, self.parse_stats.repair_attempts
+            );
+        }
+        println!(
+            "Stream events: {} tweets rate limited, {} scrub_geo, {} withheld, {} disconnects",
+            self.parse_stats.limited_tweets,
+            self.parse_stats.scrub_geo_events,
+            self.parse_stats.withheld_events,
+            self.parse_stats.disconnect_events
+        );
+        if let Some(ids) = &self.parse_options.id_filter {
+            let found: HashSet<&str> = self
+                .state
+                .tweets
+                .iter()
+                .flatten()
+                .map(|tweet| tweet.id_str.as_str())
+                .collect();
+            println!(
+                "Requested tweet ids found: {}, missing: {}",
+                found.len(),
+                ids.len() - found.len()
+            );
+        }
+
+        for tweet in self.state.tweets.iter().flatten() {
+            self.state
+                .quality_scores
+                .add(quality::tweet_quality_score(tweet));
+        }
+        print!("Quality scores:\n{}", self.state.quality_scores);
+        self.state.entities = EntityStats::from_tweets(self.state.tweets.iter().flatten());
+        println!(
+            "Entities per tweet: {:.2} hashtags, {:.2} mentions, {:.2} cashtags, {} tweets without any",
+            self.state.entities.avg_hashtags_per_tweet,
+            self.state.entities.avg_mentions_per_tweet,
+            self.state.entities.avg_cashtags_per_tweet,
+            self.state.entities.tweets_with_no_entities
+        );
+        report_top_entities(self.state.tweets.iter().flatten());
+
+        self.state.files_processed = self.state.tweets.len();
+        // Carried over from the previous run when nothing new was imported,
+        // so that `--since-run last` keeps working
+        self.state.newest_tweet_at = self
+            .state
+            .tweets
+            .iter()
+            .flatten()
+            .map(|tweet| tweet.created_at)
+            .max()
+            .or(self.state.newest_tweet_at);
+
+        if self.config.dry_run {
+            println!("Dry run, skipping the database import");
+        }
+        Ok(())
+    }
+
+    async fn insert_tweets(&mut self) -> Result<(), PipelineError> {
+        // Process database insertions sequentially since they're async operations
+        let results = std::mem::take(&mut self.state.tweets);
+        let insert_options = db::InsertOptions {
+            text_normalization: self.config.text_normalization,
+            import_mode: self.config.import_mode,
+            retry: db::RetryConfig {
+                randomization_factor: self.config.retry_jitter,
+                max_retries: self.config.max_retries,
+                bolt_timeout: Duration::from_secs(self.config.neo4j_bolt_timeout),
+            },
+            fields: self.field_set(),
+            #[cfg(feature = "extra_fields")]
+            preserve_extra_fields: self.config.preserve_extra_fields,
+            dry_run_db: self.config.dry_run_db,
+            run_id: Some(self.run_id.to_string()),
+        };
+        if self.config.estimate_eta && !self.config.dry_run_db {
+            self.estimate_eta(&results, &insert_options).await;
+        }
+        if let Some(dir) = &self.config.compress_output
+            && let Err(e) = fs::create_dir_all(dir)
+        {
+            eprintln!("Could not create {}: {}", dir.display(), e);
+            return Err(e.into());
+        }
+        let archive_names = archive::archive_names(&self.state.sources);
+        let mut sampler = self.state.sample_seed.map(StdRng::seed_from_u64);
+        if let (Some(rate), Some(seed)) = (self.config.sample_rate, self.state.sample_seed) {
+            println!("Importing a {} sample of the tweets, seed {}", rate, seed);
+        }
+        let mut low_quality_count = 0;
+        let hashtag_filter = self
+            .config
+            .hashtag_filter
+            .as_ref()
+            .map(|tags| HashtagFilter::new(tags, self.config.text_normalization));
+        let (mut hashtag_matched, mut hashtag_total) = (0, 0);
+        let mut tco_resolver = if self.config.expand_tco_urls {
+            match TcoResolver::new() {
+                Ok(resolver) => Some(resolver),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    eprintln!("Could not create the HTTP client to expand t.co URLs.");
+                    return Err(e);
+                }
+            }
+        } else {
+            None
+        };
+        let progress = Arc::new(Progress::default());
+        let reporter =
+            (self.config.progress_report_interval > 0 && !results.is_empty()).then(|| {
+                ProgressReporter::spawn(
+                    progress.clone(),
+                    Duration::from_secs(self.config.progress_report_interval),
+                    results.len() as u64,
+                    results.iter().map(Vec::len).sum::<usize>() as u64,
+                )
+            });
+        for (mut tweets, archive_name) in results.into_iter().zip(archive_names) {
+            let parsed = tweets.len() as u64;
+            if let Some(filter) = &hashtag_filter {
+                hashtag_total += tweets.len();
+                tweets.retain(|tweet| filter.matches(tweet));
+                hashtag_matched += tweets.len();
+            }
+            if self.config.exclude_protected_users {
+                let before = tweets.len();
+                tweets.retain(|tweet| !tweet.user.protected.unwrap_or(false));
+                self.protected_tweet_count += (before - tweets.len()) as u32;
+            }
+            if let Some(min_score) = self.config.min_quality_score {
+                let before = tweets.len();
+                tweets.retain(|tweet| quality::tweet_quality_score(tweet) >= min_score);
+                low_quality_count += before - tweets.len();
+            }
+            if let (Some(rate), Some(rng)) = (self.config.sample_rate, &mut sampler) {
+                tweets.retain(|_| rng.random_bool(rate));
+            }
+            if let Some(resolver) = &mut tco_resolver {
+                resolver.expand_tco_urls(&mut tweets).await;
+            }
+            if let Some(dir) = &self.config.compress_output {
+                for tweet in &mut tweets {
+                    db::normalize_tweet(tweet, self.config.text_normalization);
+                }
+                if let Err(e) = archive::write_archive(dir, &archive_name, &tweets) {
+                    eprintln!("Could not write the archive {}: {}", archive_name, e);
+                }
+            }
+            match self.sink.insert_tweets(tweets, &insert_options).await {
+                Ok(stats) => {
+                    progress.file_done(parsed, stats.tweets_inserted as u64);
+                    self.state.insert_stats += stats;
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
+                    eprintln!("Could not insert tweets into the database.");
+                    return Err(e);
+                }
+            }
+        }
+        if let Some(reporter) = reporter {
+            reporter.finish();
+        }
+        self.state.split_files.clear();
+
+        if self.config.dry_run_db {
+            println!(
+                "Dry run, {} tweets in {} batches validated with EXPLAIN and rolled back",
+                self.state.insert_stats.tweets_inserted, self.state.insert_stats.batches_ok
+            );
+            return Ok(());
+        }
+
+        println!(
+            "Tweets inserted: {} of {}",
+            self.state.insert_stats.tweets_inserted, self.state.insert_stats.tweets_attempted
+        );
+        println!(
+            "Batches inserted: {} ok, {} failed",
+            self.state.insert_stats.batches_ok, self.state.insert_stats.batches_failed
+        );
+        let total_batches = self.state.insert_stats.batches_ok
+            + self.state.insert_stats.batches_failed
+            + self.state.insert_stats.batches_skipped_duplicate;
+        println!(
+            "Transient errors retried: {} ({:.1} per 1000 batches)",
+            self.state.insert_stats.retries,
+            self.state.insert_stats.retries as f64 * 1000. / total_batches.max(1) as f64
+        );
+        if self.config.import_mode == db::ImportMode::Create {
+            println!(
+                "Tweets skipped as duplicates: {} in {} batches",
+                self.state.insert_stats.tweets_skipped_duplicate,
+                self.state.insert_stats.batches_skipped_duplicate
+            );
+        }
+        if hashtag_filter.is_some() {
+            println!(
+                "Tweets matching the hashtag filter: {} of {}",
+                hashtag_matched, hashtag_total
+            );
+        }
+        if let Some(min_score) = self.config.min_quality_score {
+            println!(
+                "{} tweets with a quality score below {} discarded",
+                low_quality_count, min_score
+            );
+        }
+        if self.config.exclude_protected_users {
+            println!(
+                "{} tweets from protected users excluded",
+                self.protected_tweet_count
+            );
+        }
+
+        self.pause_between_stages().await;
+        Ok(())
+    }
+
+    async fn build_relations(&mut self) -> Result<(), PipelineError> {
+        let relation_stats = match self.sink.build_relations().await {
+            Ok(stats) => stats,
+            Err(e) => {
+                eprintln!("{}", e);
+                eprintln!("Could not create the relations between tweets and users.");
+                return Err(e);
+            }
+        };
+
+        println!(
+            "REPLIES_TO relations added: {}",
+            relation_stats.replies_added
+        );
+        println!(
+            "MENTIONS relations added: {}",
+            relation_stats.mentions_added
+        );
+        println!(
+            "EDITED_FROM relations added: {}",
+            relation_stats.edits_added
+        );
+        println!(
+            "DIRECT_REPLY_TO_USER relations added: {}",
+            relation_stats.direct_replies_added
         );
+        self.state.relations_built = true;
+        Ok(())
+    }
 
-        db::add_replies_to_relation(self.credentials.clone())
+    async fn compute_conversation_depths(&mut self) -> Result<(), PipelineError> {
+        match db::compute_conversation_depths(self.credentials.clone()).await {
+            Ok(updated) => {
+                println!("Conversation depth set on {} tweets", updated);
+                self.state.depths_set = true;
+                Ok(())
+            }
+            Err(e) => {
+                eprintln!("Could not compute the conversation depths: {}", e);
+                Err(e)
+            }
+        }
+    }
+
+    async fn compute_depth_histogram(&mut self) -> Result<(), PipelineError> {
+        match db::compute_reply_depth_histogram(self.credentials.clone()).await {
+            Ok(histogram) => {
+                print_depth_histogram(&histogram);
+                self.state.reply_depth_histogram = histogram;
+                Ok(())
+            }
+            Err(e) => {
+                eprintln!("Could not compute the reply depth histogram: {}", e);
+                Err(e)
+            }
+        }
+    }
+
+    async fn add_temporal_properties(&self) -> Result<(), PipelineError> {
+        match db::add_temporal_indices(self.credentials.clone()).await {
+            Ok(updated) => {
+                println!("Temporal properties added to {} tweets", updated);
+                Ok(())
+            }
+            Err(e) => {
+                eprintln!("Could not add the temporal properties: {}", e);
+                Err(e)
+            }
+        }
+    }
+
+    async fn add_hashtag_trends(&self) -> Result<(), PipelineError> {
+        match db::add_hashtag_trend_properties(self.credentials.clone()).await {
+            Ok(pairs) => {
+                println!("Hashtag trends added for {} hashtag days", pairs);
+                Ok(())
+            }
+            Err(e) => {
+                eprintln!("Could not add the hashtag trends: {}", e);
+                Err(e)
+            }
+        }
+    }
+
+    // Writes the statistics of the import and records it in the graph
+    async fn finish_import(&mut self, stages: &[StageResult]) {
+        if self.config.dry_run || self.config.dry_run_db {
+            self.notify(CompletionStatus::Ok, 0).await;
+            return;
+        }
+        let state = std::mem::take(&mut self.state);
+        let graph_stats = db::fetch_graph_stats(self.credentials.clone())
             .await
-            .unwrap();
-        db::add_user_mention_relation(self.credentials.clone())
+            .unwrap_or_else(|e| {
+                eprintln!("Could not fetch the graph statistics: {}", e);
+                db::GraphStats::default()
+            });
+        let import_run = db::ImportRun {
+            id: self.run_id,
+            started_at: state.started_at,
+            completed_at: Utc::now(),
+            tweets_inserted: state.insert_stats.tweets_inserted,
+            newest_tweet_at: state.newest_tweet_at,
+            sample_rate: self.config.sample_rate,
+            sample_seed: state.sample_seed,
+        };
+        if let Err(e) = db::record_import_run(self.credentials.clone(), &import_run).await {
+            eprintln!("Could not record the import run: {}", e);
+        }
+
+        let stats = PipelineStats {
+            started_at: state.started_at,
+            completed_at: Utc::now(),
+            files_processed: state.files_processed,
+            skipped_files: state.skipped_files,
+            skipped_large_files: state.skipped_large_files,
+            tweets_parsed: self.parse_stats.tweet_num,
+            tweets_inserted: state.insert_stats.tweets_inserted,
+            retweet_percentage: self.parse_stats.retweet_num as f32
+                / self.parse_stats.tweet_num as f32
+                * 100.,
+            deleted_events: self.parse_stats.deleted,
+            parse_errors: self.parse_stats.parse_errors,
+            repair_attempts: self.parse_stats.repair_attempts,
+            repaired_lines: self.parse_stats.repaired_lines,
+            retries: state.insert_stats.retries,
+            sample_rate: self.config.sample_rate,
+            sample_seed: state.sample_seed,
+            quality_scores: state.quality_scores,
+            entities: state.entities,
+            stages: stages.to_vec(),
+            graph_stats,
+            reply_depth_histogram: state.reply_depth_histogram,
+        };
+        if let Err(e) = stats.write_json(&self.config.stats_output) {
+            eprintln!(
+                "Could not write the statistics to {}: {}",
+                self.config.stats_output.display(),
+                e
+            );
+        }
+        if self.config.generate_sample_queries {
+            let path = Path::new("sample_queries.cypher");
+            match db::create_sample_queries_file(self.credentials.clone(), path).await {
+                Ok(()) => println!("Sample queries written to {}", path.display()),
+                Err(e) => eprintln!("Could not write the sample queries: {}", e),
+            }
+        }
+        self.notify(CompletionStatus::Ok, state.insert_stats.tweets_inserted)
+            .await;
+        println!("Done!")
+    }
+
+    // Prints the expected import time, and asks before starting an import
+    // that is expected to take long
+    async fn estimate_eta(&mut self, results: &[Vec<json::Tweet>], options: &db::InsertOptions) {
+        const CALIBRATION_SAMPLE: usize = 1000;
+        const CONFIRM_ABOVE: Duration = Duration::from_secs(30 * 60);
+
+        let total = results.iter().map(Vec::len).sum();
+        let sample = results
+            .iter()
+            .flatten()
+            .take(CALIBRATION_SAMPLE)
+            .cloned()
+            .collect();
+        let estimate = match db::estimate_import_time(
+            self.credentials.clone(),
+            sample,
+            total,
+            options,
+        )
+        .await
+        {
+            Ok(estimate) => estimate,
+            Err(e) => {
+                eprintln!("Could not estimate the import time: {}", e);
+                return;
+            }
+        };
+        println!(
+            "Estimated import time for {} tweets: {:.0}s +/- {:.0}s",
+            total,
+            estimate.expected.as_secs_f64(),
+            estimate.margin.as_secs_f64()
+        );
+
+        // Stdin cannot be prompted when the tweets are piped in
+        if estimate.expected > CONFIRM_ABOVE && !self.config.confirm && self.config.input != "-" {
+            println!("Continue with the import? [y/N]");
+            let mut answer = String::new();
+            let _ = io::stdin().read_line(&mut answer);
+            if !answer.trim().eq_ignore_ascii_case("y") {
+                println!("Import cancelled");
+                self.fail(0, "import cancelled").await
+            }
+        }
+    }
+
+    // Gives Neo4j time to catch up after a bulk operation, falls back to the
+    // full delay when the running transactions cannot be listed
+    async fn pause_between_stages(&self) {
+        if self.config.inter_stage_delay_secs == 0 {
+            return;
+        }
+        let delay = Duration::from_secs(self.config.inter_stage_delay_secs);
+        match db::wait_for_idle(self.credentials.clone(), delay).await {
+            Ok(true) => (),
+            Ok(false) => println!("Neo4j still busy after {:?}, continuing", delay),
+            Err(e) => {
+                eprintln!("Could not list the running transactions: {}", e);
+                tokio::time::sleep(delay).await;
+            }
+        }
+    }
+
+    fn field_set(&self) -> db::FieldSet {
+        let mut fields = db::FieldSet::default();
+        if let Some(tweet_fields) = &self.config.tweet_fields {
+            fields.tweet_fields = tweet_fields.iter().copied().collect();
+        }
+        if let Some(user_fields) = &self.config.user_fields {
+            fields.user_fields = user_fields.iter().copied().collect();
+        }
+        fields
+    }
+
+    async fn notify(&mut self, status: CompletionStatus, tweets: usize) {
+        self.notify_with_error(status, tweets, None).await
+    }
+
+    async fn notify_with_error(
+        &mut self,
+        status: CompletionStatus,
+        tweets: usize,
+        error: Option<&str>,
+    ) {
+        if let Some(notifier) = self.notifier.take()
+            && let Err(e) = notifier.send(status, tweets).await
+        {
+            eprintln!("Could not send the completion event: {}", e);
+        }
+        // A failed webhook does not change the outcome of the run
+        if let Some(webhook) = self.webhook.take()
+            && let Err(e) = webhook.send(status, tweets, error).await
+        {
+            eprintln!("Could not call the notification webhook: {}", e);
+        }
+    }
+
+    async fn fail(&mut self, tweets: usize, error: &str) -> ! {
+        self.notify_with_error(CompletionStatus::Error, tweets, Some(error))
+            .await;
+        exit(1)
+    }
+
+    async fn run_query(&self, query: &[String]) {
+        match query {
+            [name, hashtag] if name == "tweets-for-hashtag" => {
+                let tweets = db::query_tweets_by_hashtag(
+                    self.credentials.clone(),
+                    hashtag,
+                    self.config.limit,
+                )
+                .await
+                .unwrap_or_else(|e| {
+                    eprintln!("{}", e);
+                    exit(1)
+                });
+                for tweet in tweets {
+                    println!(
+                        "{} [{}] {}",
+                        tweet.id,
+                        tweet.created_at.format("%Y-%m-%d %H:%M"),
+                        tweet.text
+                    );
+                }
+            }
+            [name] if name == "language-distribution" => {
+                let languages = db::count_tweets_by_language(self.credentials.clone())
+                    .await
+                    .unwrap_or_else(|e| {
+                        eprintln!("{}", e);
+                        exit(1)
+                    });
+                for (lang, count) in languages {
+                    println!("{} {}", lang, count);
+                }
+            }
+            _ => {
+                eprintln!("Unknown query {:?}", query.join(" "));
+                eprintln!("Available queries: tweets-for-hashtag TAG, language-distribution");
+                exit(1)
+            }
+        }
+    }
+
+    async fn run_cypher(&self, cypher: &str) {
+        let params = match &self.config.query_params {
+            Some(params) => match serde_json::from_str(params) {
+                Ok(serde_json::Value::Object(params)) => params,
+                _ => {
+                    eprintln!("--query-params must be a JSON object");
+                    exit(1)
+                }
+            },
+            None => Default::default(),
+        };
+
+        let result = db::run_cypher(self.credentials.clone(), cypher, params, |row| {
+            println!("{}", row)
+        })
+        .await;
+        if let Err(e) = result {
+            eprintln!("{}", e);
+            exit(1)
+        }
+    }
+
+    async fn import_users(&self, path: &Path) {
+        let (users, errors) = json::parse_users_file(path).unwrap_or_else(|e| {
+            eprintln!("Could not read {}: {}", path.display(), e);
+            exit(1)
+        });
+        if errors > 0 {
+            eprintln!("{} lines of {} failed to parse", errors, path.display());
+        }
+
+        let result = async {
+            db::prepare_database(
+                self.credentials.clone(),
+                Duration::from_millis(self.config.constraint_wait_ms),
+            )
+            .await?;
+            db::upsert_users_bulk(
+                self.credentials.clone(),
+                users,
+                self.config.text_normalization,
+            )
+            .await
+        }
+        .await;
+        match result {
+            Ok(written) => println!("Users imported: {}", written),
+            Err(e) => {
+                eprintln!("{}", e);
+                eprintln!("Could not import the users.");
+                exit(1)
+            }
+        }
+    }
+
+    async fn show_schema(&self) {
+        let schema = db::fetch_schema(self.credentials.clone())
             .await
-            .unwrap();
-        db::add_airline_labels(self.credentials.clone())
+            .unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                eprintln!("Could not read the schema.");
+                exit(1)
+            });
+        if self.config.json {
+            println!("{}", serde_json::to_string_pretty(&schema).unwrap());
+        } else {
+            println!("{}", schema);
+        }
+    }
+
+    async fn show_label_stats(&self, label: &str) {
+        let stats = db::label_statistics(self.credentials.clone(), Some(label))
             .await
-            .unwrap();
-        println!("Done!")
+            .unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                eprintln!("Could not compute the label statistics.");
+                exit(1)
+            });
+        if stats.is_empty() {
+            println!("No {} nodes with properties", label);
+        }
+        for (label, stats) in stats {
+            println!("{}: {} nodes", label, stats.node_count);
+            for key in &stats.property_keys {
+                let nulls = stats.null_count_by_property[key];
+                println!(
+                    "  {}: {} null ({:.1}%)",
+                    key,
+                    nulls,
+                    nulls as f64 * 100. / stats.node_count.max(1) as f64
+                );
+            }
+        }
     }
 
-    pub fn parse_files(&mut self, files: Vec<std::path::PathBuf>) -> Vec<Vec<json::Tweet>> {
-        let deleted_tweets = Arc::new(Mutex::new(0));
-        let number_of_tweets = Arc::new(Mutex::new(0));
-        let number_of_retweets = Arc::new(Mutex::new(0));
+    async fn export_nodes(&self) {
+        // Both are required by `--export-nodes`
+        let label = self.config.label.as_deref().unwrap();
+        let path = self.config.output.as_deref().unwrap();
+        let properties: Vec<&str> = self.config.properties.iter().map(String::as_str).collect();
 
-        // Process files in parallel
-        let results: Vec<_> = files
-            .par_iter()
-            .map(|file| {
-                let filename = file.to_str().unwrap().to_owned();
-                let (tweets, deleted, tweet_num, retweet_num) = json::parse_file(filename);
+        match db::export_nodes_csv(self.credentials.clone(), label, &properties, path).await {
+            Ok(rows) => println!("Exported {} {} nodes to {}", rows, label, path.display()),
+            Err(e) => {
+                eprintln!("{}", e);
+                eprintln!("Could not export the nodes.");
+                exit(1)
+            }
+        }
+    }
+
+    // Warns about circular reply chains and breaks them with `--break-cycles`
+    async fn detect_reply_cycles(&self) -> Result<(), PipelineError> {
+        let cycles = match db::find_circular_reply_chains(self.credentials.clone()).await {
+            Ok(cycles) => cycles,
+            Err(e) => {
+                eprintln!("Could not look for circular reply chains: {}", e);
+                return Err(e);
+            }
+        };
+        println!("Circular reply chains: {}", cycles.len());
+        for cycle in &cycles {
+            eprintln!("Warning: circular reply chain {}", cycle.join(" -> "));
+        }
+        if cycles.is_empty() || !self.config.break_cycles {
+            return Ok(());
+        }
+
+        match db::break_circular_reply_chains(self.credentials.clone(), &cycles).await {
+            Ok(deleted) => {
+                println!("REPLIES_TO relations deleted to break cycles: {}", deleted);
+                Ok(())
+            }
+            Err(e) => {
+                eprintln!("Could not break the circular reply chains: {}", e);
+                Err(e)
+            }
+        }
+    }
+
+    async fn check_consistency(&mut self) -> Result<(), PipelineError> {
+        self.pause_between_stages().await;
+        let report = match db::run_consistency_checks(self.credentials.clone()).await {
+            Ok(report) => report,
+            Err(e) => {
+                eprintln!("{}", e);
+                eprintln!("Could not run the consistency checks.");
+                return Err(e);
+            }
+        };
+
+        println!("Dangling REPLIES_TO relations: {}", report.dangling_replies);
+        println!("Tweets without author: {}", report.tweets_without_author);
+        println!("Users without tweets: {}", report.users_without_tweets);
 
-                // Update shared counters
-                {
-                    let mut deleted_count = deleted_tweets.lock().unwrap();
-                    *deleted_count += deleted;
+        if !report.is_consistent() {
+            eprintln!("Warning: the graph failed one or more consistency checks");
+            if self.config.strict_consistency {
+                return Err(PipelineError::Aborted(
+                    "the graph failed one or more consistency checks".to_string(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // Reports the first invalid UTF-8 byte of every file, returns false when
+    // a file is invalid and invalid sequences are not replaced
+    fn verify_utf8(&self, files: &[PathBuf]) -> bool {
+        let mut valid = true;
+        for file in files {
+            match json::find_invalid_utf8(file) {
+                Ok(None) => (),
+                Ok(Some(invalid)) => {
+                    eprintln!(
+                        "Invalid UTF-8 in {} at byte {}: {:?}",
+                        file.display(),
+                        invalid.offset,
+                        invalid.context
+                    );
+                    valid = self.config.utf8_replace_invalid;
                 }
-                {
-                    let mut tweets_count = number_of_tweets.lock().unwrap();
-                    *tweets_count += tweet_num;
+                Err(e) => eprintln!("Could not read {}: {}", file.display(), e),
+            }
+        }
+        valid
+    }
+
+    // All parsed tweets are kept in memory until they are inserted, so the peak
+    // is roughly the number of tweets times the size of a serialized tweet
+    pub fn estimate_memory_usage(&self, files: &[PathBuf]) -> u64 {
+        const SAMPLE_LINES: usize = 100;
+
+        files
+            .iter()
+            .filter_map(|path| {
+                let file_size = fs::metadata(path).ok()?.len();
+                let reader = BufReader::new(File::open(path).ok()?);
+
+                // Deleted tweet events are counted in the line size but not kept in memory
+                let (mut line_bytes, mut tweets, mut tweet_bytes) = (0u64, 0u64, 0u64);
+                for line in reader.lines().take(SAMPLE_LINES).map_while(Result::ok) {
+                    line_bytes += line.len() as u64 + 1;
+                    if !line.contains("\"delete\":") {
+                        tweets += 1;
+                        tweet_bytes += line.len() as u64;
+                    }
                 }
-                {
-                    let mut retweets_count = number_of_retweets.lock().unwrap();
-                    *retweets_count += retweet_num;
+                if tweets == 0 {
+                    return None;
                 }
 
-                // Return tweets for later async processing
-                tweets
+                // file_size / avg_line_size * share of lines that are tweets
+                let estimated_tweets = file_size * tweets / line_bytes;
+                Some(estimated_tweets * (tweet_bytes / tweets))
+            })
+            .sum()
+    }
+
+    // Reads v1.1 tweets piped into the pipeline, e.g. `curl ... | data-pipeline --input -`
+    pub fn parse_stdin(&mut self) -> Result<Vec<json::Tweet>, PipelineError> {
+        println!("Parsing tweets from stdin");
+        let started = Instant::now();
+
+        let (tweets, stats) =
+            json::parse_reader(io::stdin().lock(), "<stdin>", &self.parse_options)?;
+
+        let elapsed = started.elapsed().as_secs_f64();
+        println!(
+            "Parsed {} tweets in {:.1}s ({:.0} tweets/s)",
+            tweets.len(),
+            elapsed,
+            tweets.len() as f64 / elapsed
+        );
+        self.parse_stats = stats;
+        Ok(tweets)
+    }
+
+    // Fails with the first `--on-error abort` error of any file
+    pub fn parse_files(
+        &self,
+        files: Vec<PathBuf>,
+    ) -> Result<Vec<json::ParseResult>, PipelineError> {
+        let options = &self.parse_options;
+        let format = self.config.format;
+
+        // Process files in parallel
+        files
+            .par_iter()
+            .map(|file| parse_input_file(file, options, format))
+            .collect()
+    }
+
+    // Same as parse_files, with one blocking Tokio task per file
+    pub async fn parse_files_tokio(
+        &self,
+        files: Vec<PathBuf>,
+    ) -> Result<Vec<json::ParseResult>, PipelineError> {
+        let options = Arc::new(self.parse_options.clone());
+        let format = self.config.format;
+
+        let handles: Vec<_> = files
+            .into_iter()
+            .map(|file| {
+                let options = options.clone();
+                tokio::task::spawn_blocking(move || parse_input_file(&file, &options, format))
             })
             .collect();
 
-        self.tweet_count = *number_of_tweets.lock().unwrap();
-        self.deletet_tweet_count = *deleted_tweets.lock().unwrap();
-        self.retweet_count = *number_of_retweets.lock().unwrap();
-        results
+        let mut results = Vec::new();
+        for handle in handles {
+            results.push(handle.await.expect("A parser task panicked")?);
+        }
+        Ok(results)
+    }
+
+    // Adds up the statistics of the parsed files and reports the slowest ones,
+    // returns the tweets of each file
+    fn collect_parse_results(&mut self, results: Vec<json::ParseResult>) -> Vec<Vec<json::Tweet>> {
+        report_slowest_files(&results);
+        let mut parse_stats = json::ParseStats::default();
+        for result in &results {
+            parse_stats += result.stats;
+        }
+        self.parse_stats = parse_stats;
+        results.into_iter().map(|result| result.tweets).collect()
+    }
+}
+
+fn parse_input_file(
+    file: &Path,
+    options: &json::ParseOptions,
+    format: json::FormatVersion,
+) -> Result<json::ParseResult, PipelineError> {
+    let filename = file.to_str().unwrap().to_owned();
+    #[cfg(feature = "parquet")]
+    if file.extension().is_some_and(|ext| ext == "parquet") {
+        return match json::parse_parquet_file(filename.clone(), options) {
+            Err(e @ PipelineError::ParseAborted(_)) => Err(e),
+            Err(e) => {
+                eprintln!("Could not read {}: {}", filename, e);
+                Ok(json::ParseResult {
+                    file_path: file.to_path_buf(),
+                    ..Default::default()
+                })
+            }
+            parsed => parsed,
+        };
+    }
+    let format = match format {
+        json::FormatVersion::Auto => match json::detect_file_format(&filename) {
+            Ok(format) => format,
+            Err(e) => {
+                eprintln!("Could not read {}: {}", filename, e);
+                return Ok(json::ParseResult {
+                    file_path: file.to_path_buf(),
+                    ..Default::default()
+                });
+            }
+        },
+        format => format,
+    };
+    match format {
+        json::FormatVersion::V2 => json::parse_file_v2(filename, options),
+        _ => json::parse_file(filename, options),
+    }
+}
+
+fn print_depth_histogram(histogram: &[(u32, u64)]) {
+    const BAR_WIDTH: u64 = 40;
+
+    let max = histogram
+        .iter()
+        .map(|(_, count)| *count)
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    println!("Reply depth histogram:");
+    for (depth, count) in histogram {
+        let bin = if *depth > db::MAX_HISTOGRAM_DEPTH {
+            format!("> {}", db::MAX_HISTOGRAM_DEPTH)
+        } else {
+            depth.to_string()
+        };
+        println!(
+            "  {:>3} {:>10} {}",
+            bin,
+            count,
+            "#".repeat((count * BAR_WIDTH / max) as usize)
+        );
+    }
+}
+
+// Most used hashtags and most mentioned users of the parsed tweets
+fn report_top_entities<'a>(tweets: impl Iterator<Item = &'a json::Tweet>) {
+    const TOP: usize = 10;
+
+    let mut hashtags: HashMap<&str, u64> = HashMap::new();
+    let mut mentions: HashMap<&str, u64> = HashMap::new();
+    for tweet in tweets {
+        for tag in &tweet.entities.hashtags {
+            *hashtags.entry(tag).or_default() += 1;
+        }
+        for user_id in &tweet.entities.user_mentions {
+            *mentions.entry(user_id).or_default() += 1;
+        }
+    }
+
+    for (title, counts) in [
+        ("Top hashtags", hashtags),
+        ("Most mentioned user ids", mentions),
+    ] {
+        if counts.is_empty() {
+            continue;
+        }
+        let mut top = TopN::new(TOP);
+        for (item, count) in counts {
+            top.insert(item, count as f64);
+        }
+        println!("{}:", title);
+        for (item, count) in top.into_sorted_vec() {
+            println!("  {} {}", item, count);
+        }
+    }
+}
+
+// A high coefficient of variation means a few files are much slower than the
+// rest, e.g. because of the disk they are on, rather than all files being slow
+fn report_slowest_files(timings: &[json::ParseResult]) {
+    const SLOWEST: usize = 5;
+
+    if timings.len() < 2 {
+        return;
+    }
+    let mut slowest = TopN::new(SLOWEST);
+    for timing in timings {
+        slowest.insert(timing, timing.secs_per_tweet());
+    }
+
+    println!("Slowest files:");
+    for (timing, _) in slowest.into_sorted_vec() {
+        println!(
+            "  {} {:.2}s for {} tweets ({:.1} us/tweet)",
+            timing.file_path.display(),
+            timing.parse_duration.as_secs_f64(),
+            timing.tweets.len(),
+            timing.secs_per_tweet() * 1e6
+        );
+    }
+
+    let n = timings.len() as f64;
+    let mean = timings.iter().map(|t| t.secs_per_tweet()).sum::<f64>() / n;
+    let variance = timings
+        .iter()
+        .map(|t| (t.secs_per_tweet() - mean).powi(2))
+        .sum::<f64>()
+        / n;
+    println!(
+        "Coefficient of variation of the parse time per tweet: {:.2}",
+        variance.sqrt() / mean
+    );
+}
+
+// The stages the configuration asks for, in the order they run
+fn import_stages<S: DataSink>(config: &AppConfig) -> Vec<Box<dyn Stage<PipelineContext<S>>>> {
+    let mut stages: Vec<Box<dyn Stage<PipelineContext<S>>>> = Vec::new();
+    // Creating the constraints is a write, so `--dry-run-db` relies on the
+    // EXPLAIN queries to reach the database
+    if !config.dry_run && !config.dry_run_db {
+        stages.push(Box::new(PrepareDbStage));
+    }
+    stages.push(Box::new(ParseFilesStage));
+    if config.dry_run {
+        return stages;
+    }
+    stages.push(Box::new(InsertTweetsStage));
+    // The remaining stages write to the graph
+    if config.dry_run_db {
+        return stages;
+    }
+    stages.push(Box::new(BuildRelationsStage));
+    if config.detect_cycles {
+        stages.push(Box::new(DetectCyclesStage));
+    }
+    if config.conversation_depth {
+        stages.push(Box::new(ConversationDepthStage));
+    }
+    if config.depth_histogram {
+        stages.push(Box::new(DepthHistogramStage));
+    }
+    if config.temporal_properties {
+        stages.push(Box::new(TemporalPropertiesStage));
+    }
+    if config.hashtag_trends {
+        stages.push(Box::new(HashtagTrendsStage));
+    }
+    if config.consistency_check {
+        stages.push(Box::new(ConsistencyCheckStage));
+    }
+    stages
+}
+
+pub struct PrepareDbStage;
+
+impl<S: DataSink> Stage<PipelineContext<S>> for PrepareDbStage {
+    fn id(&self) -> StageId {
+        StageId::PrepareDb
+    }
+
+    fn execute<'a>(
+        &'a self,
+        ctx: &'a mut PipelineContext<S>,
+    ) -> LocalBoxFuture<'a, Result<(), PipelineError>> {
+        Box::pin(ctx.prepare_db())
+    }
+}
+
+pub struct ParseFilesStage;
+
+impl<S: DataSink> Stage<PipelineContext<S>> for ParseFilesStage {
+    fn id(&self) -> StageId {
+        StageId::Parse
+    }
+
+    fn execute<'a>(
+        &'a self,
+        ctx: &'a mut PipelineContext<S>,
+    ) -> LocalBoxFuture<'a, Result<(), PipelineError>> {
+        Box::pin(ctx.parse_input())
+    }
+}
+
+pub struct InsertTweetsStage;
+
+impl<S: DataSink> Stage<PipelineContext<S>> for InsertTweetsStage {
+    fn id(&self) -> StageId {
+        StageId::Insert
+    }
+
+    fn execute<'a>(
+        &'a self,
+        ctx: &'a mut PipelineContext<S>,
+    ) -> LocalBoxFuture<'a, Result<(), PipelineError>> {
+        Box::pin(ctx.insert_tweets())
+    }
+}
+
+pub struct BuildRelationsStage;
+
+impl<S: DataSink> Stage<PipelineContext<S>> for BuildRelationsStage {
+    fn id(&self) -> StageId {
+        StageId::Relations
+    }
+
+    fn execute<'a>(
+        &'a self,
+        ctx: &'a mut PipelineContext<S>,
+    ) -> LocalBoxFuture<'a, Result<(), PipelineError>> {
+        Box::pin(ctx.build_relations())
+    }
+}
+
+pub struct DetectCyclesStage;
+
+impl<S: DataSink> Stage<PipelineContext<S>> for DetectCyclesStage {
+    fn id(&self) -> StageId {
+        StageId::DetectCycles
+    }
+
+    fn required(&self) -> bool {
+        false
+    }
+
+    fn execute<'a>(
+        &'a self,
+        ctx: &'a mut PipelineContext<S>,
+    ) -> LocalBoxFuture<'a, Result<(), PipelineError>> {
+        Box::pin(ctx.detect_reply_cycles())
+    }
+}
+
+pub struct ConversationDepthStage;
+
+impl<S: DataSink> Stage<PipelineContext<S>> for ConversationDepthStage {
+    fn id(&self) -> StageId {
+        StageId::ConversationDepth
+    }
+
+    fn required(&self) -> bool {
+        false
+    }
+
+    fn execute<'a>(
+        &'a self,
+        ctx: &'a mut PipelineContext<S>,
+    ) -> LocalBoxFuture<'a, Result<(), PipelineError>> {
+        Box::pin(ctx.compute_conversation_depths())
     }
 }
 
-impl Default for App {
-    fn default() -> Self {
+pub struct DepthHistogramStage;
+
+impl<S: DataSink> Stage<PipelineContext<S>> for DepthHistogramStage {
+    fn id(&self) -> StageId {
+        StageId::DepthHistogram
+    }
+
+    // Only meaningful with the depths of this run's relations
+    fn should_run(&self, ctx: &PipelineContext<S>) -> bool {
+        ctx.state.relations_built && ctx.state.depths_set
+    }
+
+    fn required(&self) -> bool {
+        false
+    }
+
+    fn execute<'a>(
+        &'a self,
+        ctx: &'a mut PipelineContext<S>,
+    ) -> LocalBoxFuture<'a, Result<(), PipelineError>> {
+        Box::pin(ctx.compute_depth_histogram())
+    }
+}
+
+pub struct TemporalPropertiesStage;
+
+impl<S: DataSink> Stage<PipelineContext<S>> for TemporalPropertiesStage {
+    fn id(&self) -> StageId {
+        StageId::TemporalProperties
+    }
+
+    fn required(&self) -> bool {
+        false
+    }
+
+    fn execute<'a>(
+        &'a self,
+        ctx: &'a mut PipelineContext<S>,
+    ) -> LocalBoxFuture<'a, Result<(), PipelineError>> {
+        Box::pin(ctx.add_temporal_properties())
+    }
+}
+
+pub struct HashtagTrendsStage;
+
+impl<S: DataSink> Stage<PipelineContext<S>> for HashtagTrendsStage {
+    fn id(&self) -> StageId {
+        StageId::HashtagTrends
+    }
+
+    fn required(&self) -> bool {
+        false
+    }
+
+    fn execute<'a>(
+        &'a self,
+        ctx: &'a mut PipelineContext<S>,
+    ) -> LocalBoxFuture<'a, Result<(), PipelineError>> {
+        Box::pin(ctx.add_hashtag_trends())
+    }
+}
+
+pub struct ConsistencyCheckStage;
+
+impl<S: DataSink> Stage<PipelineContext<S>> for ConsistencyCheckStage {
+    fn id(&self) -> StageId {
+        StageId::ConsistencyCheck
+    }
+
+    fn execute<'a>(
+        &'a self,
+        ctx: &'a mut PipelineContext<S>,
+    ) -> LocalBoxFuture<'a, Result<(), PipelineError>> {
+        Box::pin(ctx.check_consistency())
+    }
+}
+
+impl App<Neo4jSink> {
+    pub fn new(config: AppConfig) -> Self {
         let credentials: db::Credentials =
             get_credentials_from_env().expect("Could not load environment variable");
+        let credentials = Arc::new(credentials);
+        let sink = Neo4jSink::new(credentials.clone(), config.user_mentions_strategy);
+        Self::with_sink(config, credentials, sink)
+    }
+}
+
+impl<S: DataSink> App<S> {
+    pub fn with_sink(config: AppConfig, credentials: Arc<db::Credentials>, sink: S) -> Self {
+        if let Some(prefix) = &config.tenant_prefix {
+            db::set_tenant_prefix(prefix.clone());
+        }
+        let id_filter = config
+            .id_list
+            .as_ref()
+            .map(|path| load_id_list(path).expect("Could not read the tweet id list"));
+        let webhook = config
+            .notify_webhook
+            .clone()
+            .map(|url| WebhookNotifier::new(url, Duration::from_secs(config.notify_timeout_secs)));
+        let parse_options = json::ParseOptions {
+            use_mmap: config.mmap_files,
+            id_filter,
+            since: None,
+            keep_raw_json: config.store_raw_json,
+            replace_invalid_utf8: config.utf8_replace_invalid,
+            on_error: config.on_error,
+        };
         Self {
-            credentials,
-            tweet_count: Default::default(),
-            deletet_tweet_count: Default::default(),
-            retweet_count: Default::default(),
+            ctx: PipelineContext {
+                sink,
+                credentials,
+                config,
+                parse_options,
+                parse_stats: Default::default(),
+                protected_tweet_count: Default::default(),
+                notifier: None,
+                webhook,
+                run_id: Uuid::new_v4(),
+                state: ImportState::default(),
+            },
         }
     }
 }
@@ -121,3 +1608,316 @@ fn get_credentials_from_env() -> Result<db::Credentials, VarError> {
         password,
     })
 }
+
+// Each path to parse with the input file it comes from, and the chunks the
+// paths point into
+type SplitFiles = (Vec<(PathBuf, PathBuf)>, Vec<NamedTempFile>);
+
+// Replaces the JSON files with more than `max_lines` lines by chunks of at most
+// `max_lines` lines. The chunks are returned so that they live as long as needed
+fn split_large_files(files: Vec<PathBuf>, max_lines: usize) -> Result<SplitFiles, PipelineError> {
+    let mut paths = Vec::new();
+    let mut chunks = Vec::new();
+    for file in files {
+        let is_parquet = file.extension().is_some_and(|ext| ext == "parquet");
+        if is_parquet || count_lines(&file)? <= max_lines {
+            paths.push((file.clone(), file));
+            continue;
+        }
+        let file_chunks = split_file(&file, max_lines)?;
+        println!("Split {} into {} chunks", file.display(), file_chunks.len());
+        paths.extend(
+            file_chunks
+                .iter()
+                .map(|chunk| (chunk.path().to_owned(), file.clone())),
+        );
+        chunks.extend(file_chunks);
+    }
+    Ok((paths, chunks))
+}
+
+fn count_lines(path: &Path) -> io::Result<usize> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut count = 0;
+    loop {
+        let buffer = reader.fill_buf()?;
+        if buffer.is_empty() {
+            return Ok(count);
+        }
+        count += buffer.iter().filter(|byte| **byte == b'\n').count();
+        let consumed = buffer.len();
+        reader.consume(consumed);
+    }
+}
+
+// Writes the lines of `path` to temporary files of at most `max_lines` lines,
+// which are deleted when dropped
+fn split_file(path: &Path, max_lines: usize) -> Result<Vec<NamedTempFile>, PipelineError> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut chunks = Vec::new();
+    let mut writer = BufWriter::new(NamedTempFile::new()?);
+    let mut lines_in_chunk = 0;
+
+    for line in reader.split(b'\n') {
+        let line = line?;
+        if lines_in_chunk == max_lines {
+            let full = std::mem::replace(&mut writer, BufWriter::new(NamedTempFile::new()?));
+            chunks.push(full.into_inner().map_err(|e| e.into_error())?);
+            lines_in_chunk = 0;
+        }
+        writer.write_all(&line)?;
+        writer.write_all(b"\n")?;
+        lines_in_chunk += 1;
+    }
+    if lines_in_chunk > 0 {
+        chunks.push(writer.into_inner().map_err(|e| e.into_error())?);
+    }
+    Ok(chunks)
+}
+
+// Checks that the file starts like JSON, `{` or `[` after an optional BOM, so
+// that logs or temporary files matched by the input pattern are not parsed.
+// Parquet files and empty files pass
+fn looks_like_json(path: &Path) -> bool {
+    if path.extension().is_some_and(|ext| ext == "parquet") {
+        return true;
+    }
+    let mut head = [0u8; 16];
+    let read = match File::open(path).and_then(|mut file| file.read(&mut head)) {
+        Ok(read) => read,
+        Err(e) => {
+            eprintln!("Warning: skipping {}: {}", path.display(), e);
+            return false;
+        }
+    };
+    let head = &head[..read];
+    let content = head.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(head);
+    if matches!(content.first(), None | Some(b'{' | b'[')) {
+        return true;
+    }
+    let hex: Vec<String> = head.iter().map(|byte| format!("{:02x}", byte)).collect();
+    eprintln!(
+        "Warning: skipping {}, it does not look like JSON. First bytes: {}",
+        path.display(),
+        hex.join(" ")
+    );
+    false
+}
+
+fn within_size_limit(path: &Path, max_size: u64) -> bool {
+    match fs::metadata(path) {
+        Ok(metadata) if metadata.len() > max_size => {
+            eprintln!(
+                "Warning: skipping {}, {} MB is over the --max-file-size limit. \
+                Split it first, or use --split-threshold-lines",
+                path.display(),
+                metadata.len() / 1024 / 1024
+            );
+            false
+        }
+        Ok(_) => true,
+        Err(e) => {
+            eprintln!("Warning: skipping {}: {}", path.display(), e);
+            false
+        }
+    }
+}
+
+fn load_id_list(path: &Path) -> io::Result<HashSet<String>> {
+    let content = fs::read_to_string(path)?;
+    Ok(content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_owned)
+        .collect())
+}
+
+// Explains an input pattern that matched nothing, and suggests the file in the
+// searched directory whose name is closest to the pattern
+fn report_unmatched_pattern(pattern: &str) {
+    let pattern = Path::new(pattern);
+    let searched = std::path::absolute(pattern).unwrap_or_else(|_| pattern.to_owned());
+    eprintln!("No input file matches {}", searched.display());
+
+    let dir = searched.parent().unwrap_or(Path::new("/"));
+    let mut names: Vec<String> = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(Result::ok)
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .collect(),
+        Err(e) => {
+            eprintln!("Could not list {}: {}", dir.display(), e);
+            return;
+        }
+    };
+    if names.is_empty() {
+        eprintln!("{} is empty", dir.display());
+        return;
+    }
+    names.sort();
+    eprintln!("Files in {}:", dir.display());
+    for name in &names {
+        eprintln!("  {}", name);
+    }
+
+    let file_pattern = searched
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    if let Some(closest) = names
+        .iter()
+        .min_by_key(|name| edit_distance(&file_pattern, name))
+    {
+        eprintln!("Did you mean {}?", dir.join(closest).display());
+    }
+}
+
+// Levenshtein distance between two strings, counted in chars
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::InMemorySink;
+    use clap::Parser;
+
+    // The statistics read from Neo4j at the end of a run fail on the scheme,
+    // without trying to connect
+    fn app(args: &[&str], stats_output: &Path) -> App<InMemorySink> {
+        let input = format!("{}/tests/fixtures/crlf.jsonl", env!("CARGO_MANIFEST_DIR"));
+        let mut argv = vec![
+            "data-pipeline",
+            "--input",
+            &input,
+            "--stats-output",
+            stats_output.to_str().unwrap(),
+            "--progress-report-interval",
+            "0",
+        ];
+        argv.extend(args);
+        let credentials = Arc::new(Credentials {
+            uri: "none://localhost".to_string(),
+            user: "neo4j".to_string(),
+            password: "neo4j".to_string(),
+        });
+        App::with_sink(
+            AppConfig::parse_from(argv),
+            credentials,
+            InMemorySink::default(),
+        )
+    }
+
+    fn inserted_ids(app: &App<InMemorySink>) -> Vec<String> {
+        let mut ids: Vec<_> = app
+            .ctx
+            .sink
+            .tweets()
+            .into_iter()
+            .map(|t| t.id_str)
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    #[tokio::test]
+    async fn run_inserts_the_parsed_tweets_into_the_sink() {
+        let dir = tempfile::tempdir().unwrap();
+        let stats_output = dir.path().join("stats.json");
+        let mut app = app(&["--skip-stage", "prepare-db"], &stats_output);
+        app.run().await;
+
+        assert_eq!(
+            inserted_ids(&app),
+            ["1131580395845767168", "1131581971696091136"]
+        );
+        let stats: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&stats_output).unwrap()).unwrap();
+        assert_eq!(stats["tweets_parsed"], 2);
+        assert_eq!(stats["tweets_inserted"], 2);
+    }
+
+    #[tokio::test]
+    async fn dry_run_leaves_the_sink_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut app = app(&["--dry-run"], &dir.path().join("stats.json"));
+        app.run().await;
+
+        assert!(inserted_ids(&app).is_empty());
+        assert_eq!(app.ctx.parse_stats.tweet_num, 2);
+    }
+
+    #[tokio::test]
+    async fn filters_run_before_the_sink() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut app = app(
+            &["--skip-stage", "prepare-db", "--hashtag-filter", "KLM"],
+            &dir.path().join("stats.json"),
+        );
+        app.run().await;
+
+        assert_eq!(inserted_ids(&app), ["1131580395845767168"]);
+    }
+
+    fn stage_ids(config: &AppConfig) -> Vec<StageId> {
+        import_stages::<InMemorySink>(config)
+            .iter()
+            .map(|stage| stage.id())
+            .collect()
+    }
+
+    #[test]
+    fn dry_runs_leave_out_the_stages_that_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let stats_output = dir.path().join("stats.json");
+
+        let dry_run = app(&["--dry-run"], &stats_output);
+        assert_eq!(stage_ids(&dry_run.ctx.config), [StageId::Parse]);
+
+        let dry_run_db = app(&["--dry-run-db", "--detect-cycles"], &stats_output);
+        assert_eq!(
+            stage_ids(&dry_run_db.ctx.config),
+            [StageId::Parse, StageId::Insert]
+        );
+    }
+
+    #[tokio::test]
+    async fn parse_stage_hands_the_tweets_to_the_insert_stage() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut app = app(&[], &dir.path().join("stats.json"));
+        let ctx = &mut app.ctx;
+
+        ParseFilesStage.execute(ctx).await.unwrap();
+        assert_eq!(ctx.state.tweets.iter().flatten().count(), 2);
+        assert_eq!(ctx.state.files_processed, 1);
+        assert!(ctx.sink.tweets().is_empty());
+
+        InsertTweetsStage.execute(ctx).await.unwrap();
+        assert!(ctx.state.tweets.is_empty());
+        assert_eq!(ctx.state.insert_stats.tweets_inserted, 2);
+        assert_eq!(ctx.sink.tweets().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn parse_stage_fails_when_no_file_matches() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut app = app(&[], &dir.path().join("stats.json"));
+        app.ctx.config.input = dir.path().join("*.jsonl").display().to_string();
+
+        let error = ParseFilesStage.execute(&mut app.ctx).await.unwrap_err();
+
+        assert_eq!(error.to_string(), "no input file matches the input pattern");
+    }
+}
@@ -0,0 +1,56 @@
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::json::Tweet;
+
+// Name of the archive of each parsed file, from the input file it was read
+// from. The chunks of a split input file are numbered
+pub fn archive_names(sources: &[PathBuf]) -> Vec<String> {
+    let mut chunks: HashMap<&Path, usize> = HashMap::new();
+    for source in sources {
+        *chunks.entry(source).or_default() += 1;
+    }
+
+    let mut parts: HashMap<&Path, usize> = HashMap::new();
+    sources
+        .iter()
+        .map(|source| {
+            let name = source
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_else(|| "stdin".to_owned());
+            if chunks[source.as_path()] > 1 {
+                let part = parts.entry(source).or_default();
+                *part += 1;
+                format!("{}.part{}.jsonl.gz", name, part)
+            } else {
+                format!("{}.jsonl.gz", name)
+            }
+        })
+        .collect()
+}
+
+// Writes the tweets as gzipped JSON lines to `dir/name`, next to a
+// `name.sha256` file that `sha256sum -c` can check
+pub fn write_archive(dir: &Path, name: &str, tweets: &[Tweet]) -> io::Result<PathBuf> {
+    let path = dir.join(name);
+    let mut encoder = GzEncoder::new(BufWriter::new(File::create(&path)?), Compression::default());
+    for tweet in tweets {
+        serde_json::to_writer(&mut encoder, tweet)?;
+        encoder.write_all(b"\n")?;
+    }
+    encoder.finish()?.flush()?;
+
+    let digest = Sha256::digest(fs::read(&path)?);
+    let hex: String = digest.iter().map(|byte| format!("{:02x}", byte)).collect();
+    fs::write(
+        dir.join(format!("{}.sha256", name)),
+        format!("{}  {}\n", hex, name),
+    )?;
+    Ok(path)
+}
@@ -0,0 +1,358 @@
+use clap::Parser;
+use std::path::PathBuf;
+
+use crate::db::{ImportMode, TweetField, UnicodeNormForm, UserField, UserMentionStrategy};
+use crate::json::{FormatVersion, ParseErrorStrategy, ParserExecutor};
+use crate::stage::StageId;
+
+const LONG_VERSION: &str = concat!(
+    env!("CARGO_PKG_VERSION"),
+    "\ncommit: ",
+    env!("VERGEN_GIT_SHA"),
+    "\nbuilt:  ",
+    env!("VERGEN_BUILD_TIMESTAMP"),
+    "\ntarget: ",
+    env!("VERGEN_CARGO_TARGET_TRIPLE"),
+);
+
+#[derive(Debug, Parser, Clone)]
+#[command(version, long_version = LONG_VERSION, about)]
+pub struct AppConfig {
+    /// Glob pattern of the files to import, or `-` to read tweets from stdin
+    #[arg(long, default_value = "/data/airlines-*.json")]
+    pub input: String,
+
+    /// Skip tweets posted by protected accounts when inserting into the database
+    #[arg(long)]
+    pub exclude_protected_users: bool,
+
+    /// Read input files through a memory map instead of buffered reads.
+    /// Avoid on network filesystems, where the file may change while mapped
+    #[arg(long)]
+    pub mmap_files: bool,
+
+    /// Run graph consistency checks after the import and warn about problems
+    #[arg(long)]
+    pub consistency_check: bool,
+
+    /// Exit with a non-zero code when the consistency checks find problems
+    #[arg(long, requires = "consistency_check")]
+    pub strict_consistency: bool,
+
+    /// Twitter API format of the input files
+    #[arg(long, value_enum, default_value_t = FormatVersion::Auto)]
+    pub format: FormatVersion,
+
+    /// Only import the tweets whose ids are listed in this file, one id per line
+    #[arg(long, value_name = "PATH")]
+    pub id_list: Option<PathBuf>,
+
+    /// Parse the input files and report statistics without touching the database
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Run a query against the imported graph instead of importing,
+    /// e.g. `--query tweets-for-hashtag TAG` or `--query language-distribution`
+    #[arg(long, num_args = 1..=2, value_names = ["QUERY", "ARG"])]
+    pub query: Option<Vec<String>>,
+
+    /// Maximum number of rows returned by `--query`
+    #[arg(long, default_value_t = 10)]
+    pub limit: usize,
+
+    /// Warn when the estimated memory usage exceeds this many bytes
+    /// [default: 80% of the available memory]
+    #[arg(long, value_name = "BYTES")]
+    pub memory_limit: Option<u64>,
+
+    /// Unicode normalization applied to hashtags and screen names before insertion
+    #[arg(long, value_enum, default_value_t = UnicodeNormForm::Nfc)]
+    pub text_normalization: UnicodeNormForm,
+
+    /// Drop the constraints and indexes created by the pipeline instead of importing
+    #[arg(long, requires = "confirm")]
+    pub drop_schema: bool,
+
+    /// Confirm a destructive operation such as `--drop-schema` or `--purge-run`,
+    /// or a long import announced by `--estimate-eta`
+    #[arg(long)]
+    pub confirm: bool,
+
+    /// Where to write the JSON statistics of the run
+    #[arg(long, value_name = "PATH", default_value = "./pipeline_stats.json")]
+    pub stats_output: PathBuf,
+
+    /// How tweets that already exist in the database are handled
+    #[arg(long, value_enum, default_value_t = ImportMode::Merge)]
+    pub import_mode: ImportMode,
+
+    /// Unix domain socket on which a one-line JSON completion event is written
+    #[arg(long, value_name = "PATH")]
+    pub notify_socket: Option<PathBuf>,
+
+    /// Export nodes to a CSV file instead of importing
+    #[arg(long, requires_all = ["label", "output"])]
+    pub export_nodes: bool,
+
+    /// Label of the nodes to export with `--export-nodes`
+    #[arg(long)]
+    pub label: Option<String>,
+
+    /// Comma separated node properties to export with `--export-nodes`
+    #[arg(long, value_delimiter = ',', default_value = "id")]
+    pub properties: Vec<String>,
+
+    /// CSV file written by `--export-nodes`
+    #[arg(long, value_name = "PATH")]
+    pub output: Option<PathBuf>,
+
+    /// Random jitter applied to retry delays, as a share of the delay
+    #[arg(long, default_value_t = 0.5)]
+    pub retry_jitter: f64,
+
+    /// Only import tweets newer than the newest tweet of this run, `last` for the latest run
+    #[arg(long, value_name = "RUN_ID")]
+    pub since_run: Option<String>,
+
+    /// Maximum time to wait for the constraints to be applied before importing
+    #[arg(long, value_name = "MS", default_value_t = 500)]
+    pub constraint_wait_ms: u64,
+
+    /// Prefix added to the node labels, e.g. `airlines2019` gives `airlines2019_Tweet`,
+    /// so that several imports can share one database
+    #[arg(long, value_name = "PREFIX", value_parser = parse_tenant_prefix)]
+    pub tenant_prefix: Option<String>,
+
+    /// Print the constraints, indexes, labels and relationship types of the database
+    #[arg(long)]
+    pub show_schema: bool,
+
+    /// Print the output of `--show-schema` as JSON
+    #[arg(long, requires = "show_schema")]
+    pub json: bool,
+
+    /// Store the original JSON line of every tweet in `t.raw_json`, about 1 KB per tweet
+    #[arg(long)]
+    pub store_raw_json: bool,
+
+    /// Time a sample insert to estimate the import duration before importing
+    #[arg(long)]
+    pub estimate_eta: bool,
+
+    /// Write example Cypher queries for the imported graph to `sample_queries.cypher`,
+    /// for the labels, relationships and properties the graph holds
+    #[arg(long)]
+    pub generate_sample_queries: bool,
+
+    /// Comma separated tweet properties to import, all of them by default
+    #[arg(long, value_enum, value_delimiter = ',')]
+    pub tweet_fields: Option<Vec<TweetField>>,
+
+    /// Comma separated user properties to import, all of them by default
+    #[arg(long, value_enum, value_delimiter = ',')]
+    pub user_fields: Option<Vec<UserField>>,
+
+    /// Maximum pause between the import stages, ended early once Neo4j has no
+    /// running transaction left
+    #[arg(long, value_name = "SECS", default_value_t = 0)]
+    pub inter_stage_delay_secs: u64,
+
+    /// Add integer year, month and day properties to the tweets, with a (year, month) index
+    #[arg(long)]
+    pub temporal_properties: bool,
+
+    /// Store the tweet fields the pipeline does not know about as JSON in `t.extra_json`
+    #[cfg(feature = "extra_fields")]
+    #[arg(long)]
+    pub preserve_extra_fields: bool,
+
+    /// Run a Cypher query and print every row as JSON instead of importing
+    #[arg(long, value_name = "CYPHER")]
+    pub neo4j_query: Option<String>,
+
+    /// JSON object with the parameters of `--neo4j-query`, e.g. '{"tag": "klm"}'
+    #[arg(long, value_name = "JSON", requires = "neo4j_query")]
+    pub query_params: Option<String>,
+
+    /// Give up on a batch after this many retries of the same kind of error,
+    /// even if the backoff time limit is not reached
+    #[arg(long, value_name = "N")]
+    pub max_retries: Option<u32>,
+
+    /// Split input files with more lines than this into temporary chunks, so that
+    /// a single large file is parsed in parallel
+    #[arg(long, value_name = "N")]
+    pub split_threshold_lines: Option<usize>,
+
+    /// Check that every input file is valid UTF-8 before parsing
+    #[arg(long)]
+    pub verify_utf8: bool,
+
+    /// Replace invalid UTF-8 with U+FFFD instead of rejecting the file or line
+    #[arg(long)]
+    pub utf8_replace_invalid: bool,
+
+    /// Write the whole graph as Cypher statements to this file instead of importing
+    #[arg(long, value_name = "PATH")]
+    pub backup: Option<PathBuf>,
+
+    /// Parse and send every batch to Neo4j as `EXPLAIN` queries, rolled back
+    /// instead of committed, to validate the queries without writing anything
+    #[arg(long, conflicts_with = "dry_run")]
+    pub dry_run_db: bool,
+
+    /// Discard the tweets whose quality score, the share of optional fields
+    /// that are populated, is below this value
+    #[arg(long, value_name = "SCORE")]
+    pub min_quality_score: Option<f32>,
+
+    /// Also write the parsed tweets of every input file, after filtering and
+    /// normalization, to `DIR/<file name>.jsonl.gz` with a SHA-256 checksum
+    #[arg(long, value_name = "DIR")]
+    pub compress_output: Option<PathBuf>,
+
+    /// Keep each tweet with this probability, to import a sample of the dataset
+    #[arg(long, value_name = "RATE", value_parser = parse_sample_rate)]
+    pub sample_rate: Option<f64>,
+
+    /// Seed of `--sample-rate`, so that the same sample can be imported again.
+    /// A random seed is used and reported when missing
+    #[arg(long, value_name = "SEED", requires = "sample_rate")]
+    pub sample_seed: Option<u64>,
+
+    /// Set `t.conversation_depth`, the distance of each tweet to the root of its
+    /// reply chain, after the relations are built
+    #[arg(long)]
+    pub conversation_depth: bool,
+
+    /// Comma separated stages to skip, e.g. `relations,consistency-check`
+    #[arg(long, value_enum, value_delimiter = ',')]
+    pub skip_stage: Vec<StageId>,
+
+    /// Comma separated stages to run, skipping all the others,
+    /// e.g. `relations` to rebuild the relations of an existing import
+    #[arg(long, value_enum, value_delimiter = ',', conflicts_with = "skip_stage")]
+    pub only_stage: Vec<StageId>,
+
+    /// Import the users of a JSON lines file of user objects, without any tweet,
+    /// instead of importing tweets
+    #[arg(long, value_name = "PATH")]
+    pub import_users_only: Option<PathBuf>,
+
+    /// Skip input files larger than this many bytes, unless
+    /// `--split-threshold-lines` is set to split them instead
+    #[arg(long, value_name = "BYTES")]
+    pub max_file_size: Option<u64>,
+
+    /// Link hashtags to the days they were used on, with the daily tweet volume.
+    /// Needs the Hashtag nodes of a previous `--migrate-hashtags` run
+    #[arg(long, requires = "temporal_properties")]
+    pub hashtag_trends: bool,
+
+    /// What to do with lines that fail to parse
+    #[arg(long, value_enum, default_value_t = ParseErrorStrategy::Skip)]
+    pub on_error: ParseErrorStrategy,
+
+    /// URL to POST a JSON completion event to once the run is over, e.g. a
+    /// Slack incoming webhook
+    #[arg(long, value_name = "URL")]
+    pub notify_webhook: Option<String>,
+
+    /// Timeout of the `--notify-webhook` request
+    #[arg(long, value_name = "SECS", default_value_t = 5)]
+    pub notify_timeout_secs: u64,
+
+    /// Estimate the store size of the graph and recommend a page cache size
+    /// instead of importing
+    #[arg(long)]
+    pub optimize_graph: bool,
+
+    /// How the users mentioned by a tweet are stored
+    #[arg(long, value_enum, default_value_t = UserMentionStrategy::Both)]
+    pub user_mentions_strategy: UserMentionStrategy,
+
+    /// Executor the input files are parsed on
+    #[arg(long, value_enum, default_value_t = ParserExecutor::Rayon)]
+    pub parser: ParserExecutor,
+
+    /// Look for circular REPLIES_TO chains after the relations are built, an
+    /// expensive query, and warn about them
+    #[arg(long)]
+    pub detect_cycles: bool,
+
+    /// Delete one REPLIES_TO relation of every circular chain found by `--detect-cycles`
+    #[arg(long, requires = "detect_cycles")]
+    pub break_cycles: bool,
+
+    /// Abandon and retry a batch that Neo4j has not answered after this many seconds
+    #[arg(long, value_name = "SECS", default_value_t = 120)]
+    pub neo4j_bolt_timeout: u64,
+
+    /// Comma separated hashtags, only the tweets using at least one of them are
+    /// imported. Compared case insensitively, with or without the `#`
+    #[arg(long, value_delimiter = ',', value_name = "TAGS")]
+    pub hashtag_filter: Option<Vec<String>>,
+
+    /// Create Hashtag nodes and TAGGED_WITH relations from the `hashtags` array
+    /// of the imported tweets instead of importing
+    #[arg(long)]
+    pub migrate_hashtags: bool,
+
+    /// Delete the tweets imported by this run, except those another run
+    /// imported too, instead of importing
+    #[arg(long, value_name = "RUN_ID", requires = "confirm")]
+    pub purge_run: Option<String>,
+
+    /// Print the import progress and throughput every this many seconds, 0 to disable
+    #[arg(long, value_name = "SECS", default_value_t = 60)]
+    pub progress_report_interval: u64,
+
+    /// Print the node count and the number of nodes missing each property of
+    /// a label instead of importing, e.g. `--label-stats Tweet`
+    #[arg(long, value_name = "LABEL")]
+    pub label_stats: Option<String>,
+
+    /// Resolve the t.co links without an expanded URL with HEAD requests to
+    /// t.co, at most 100 per second
+    #[arg(long)]
+    pub expand_tco_urls: bool,
+
+    /// Link the users sharing hashtags with SIMILAR_TO relations instead of
+    /// importing. Needs the relations of `--migrate-hashtags`, which can run first
+    /// in the same call. Compares every pair of users of a hashtag, so popular
+    /// hashtags make it quadratic in the users
+    #[arg(long)]
+    pub user_similarity_graph: bool,
+
+    /// Hashtags two users must share to be similar
+    #[arg(long, value_name = "N", default_value_t = 2)]
+    pub min_shared_hashtags: u32,
+
+    /// Most similar users linked to each user
+    #[arg(long, value_name = "K", default_value_t = 10)]
+    pub similarity_top_k: u32,
+
+    /// Report the number of tweets per reply depth once the relations are built,
+    /// from the depths set by `--conversation-depth`
+    #[arg(long, requires = "conversation_depth")]
+    pub depth_histogram: bool,
+}
+
+fn parse_tenant_prefix(prefix: &str) -> Result<String, String> {
+    if !prefix.is_empty()
+        && prefix
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        Ok(prefix.to_owned())
+    } else {
+        Err("only letters, digits and underscores are allowed".to_owned())
+    }
+}
+
+fn parse_sample_rate(rate: &str) -> Result<f64, String> {
+    match rate.parse::<f64>() {
+        Ok(rate) if (0.0..=1.0).contains(&rate) => Ok(rate),
+        _ => Err("expected a number between 0.0 and 1.0".to_owned()),
+    }
+}
@@ -1,16 +1,33 @@
-use backoff::{Error as BackoffError, ExponentialBackoff};
+use backoff::ExponentialBackoff;
+use backoff::backoff::Backoff;
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
 use futures::future;
-use neo4rs::{self, Graph, query};
+use neo4rs::{self, Graph};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::sync::Arc;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::ops::AddAssign;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
 use std::time::Duration;
-use std::u8;
-use tokio;
 use tokio::sync::Semaphore;
+use unicode_normalization::UnicodeNormalization;
+use uuid::Uuid;
 
+use crate::error::PipelineError;
 use crate::json;
 
+mod graph_client;
+#[cfg(test)]
+mod mock_db;
+mod query_builder;
+use graph_client::{GraphClient, Statement};
+use query_builder::QueryBuilder;
+
 const AIRLINE_IDS: [&str; 13] = [
     "56377143",   // KLM
     "106062176",  // Air France
@@ -27,314 +44,3639 @@ const AIRLINE_IDS: [&str; 13] = [
     "20626359",   // Virgin Atlantic
 ];
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Credentials {
     pub uri: String,
     pub user: String,
     pub password: String,
 }
 
-pub async fn prepare_database(creds: Credentials) -> Result<(), neo4rs::Error> {
-    let graph = Graph::new(creds.uri, creds.user, creds.password)
-        .await
-        .unwrap();
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RelationStats {
+    pub replies_added: u64,
+    pub mentions_added: u64,
+    pub edits_added: u64,
+    pub direct_replies_added: u64,
+}
 
-    let mut txn = graph.start_txn().await?;
-    // Run this BEFORE starting any imports to ensure uniqueness of users
-    txn.run(query(
-        "
-            CREATE CONSTRAINT IF NOT EXISTS FOR (u:User) REQUIRE u.id IS UNIQUE;
-            ",
-    ))
-    .await
-    .unwrap();
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MigrationStats {
+    pub hashtags_created: u64,
+    // Hashtags of the arrays that already had a node
+    pub hashtags_existing: u64,
+    pub relations_created: u64,
+}
 
-    txn.run(query(
-        "
-            CREATE CONSTRAINT IF NOT EXISTS FOR (t:Tweet) REQUIRE t.id IS UNIQUE;
-            ",
-    ))
-    .await
-    .unwrap();
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PurgeReport {
+    pub tweets_deleted: u64,
+    pub relationships_deleted: u64,
+    // Also imported by another run, only the purged run is removed from them
+    pub tweets_retained: u64,
+}
 
-    txn.commit().await?;
+// Property fill rate of the nodes of one label
+#[derive(Debug, Default, Clone)]
+pub struct LabelStats {
+    pub node_count: u64,
+    pub property_keys: Vec<String>,
+    pub null_count_by_property: HashMap<String, u64>,
+}
 
-    // Wait a moment for the constraint to be fully applied
-    println!("Waiting for the constraint to be applied...");
-    tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+#[derive(Debug, Clone)]
+pub struct TweetSummary {
+    pub id: String,
+    pub text: String,
+    pub created_at: DateTime<Utc>,
+}
 
-    Ok(())
+// A completed import, stored as an ImportRun node so later runs can pick up
+// where it stopped
+#[derive(Debug, Clone)]
+pub struct ImportRun {
+    pub id: Uuid,
+    pub started_at: DateTime<Utc>,
+    pub completed_at: DateTime<Utc>,
+    pub tweets_inserted: usize,
+    pub newest_tweet_at: Option<DateTime<Utc>>,
+    // Set when only a sample of the tweets was imported
+    pub sample_rate: Option<f64>,
+    pub sample_seed: Option<u64>,
 }
 
-pub async fn insert_new_tweets(creds: Credentials, tweets: Vec<json::Tweet>) {
-    let graph = Graph::new(creds.uri, creds.user, creds.password)
-        .await
-        .unwrap();
+// Unicode normalization applied to hashtags and screen names, so the same
+// text typed on different clients ends up as the same value in the graph
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum UnicodeNormForm {
+    None,
+    #[default]
+    Nfc,
+    Nfkc,
+}
 
-    let batch_size = 500; // How many nodes per transaction
-    let max_concurrent_batches = 8; // Limit concurrent transactions
+impl UnicodeNormForm {
+    pub fn apply(self, text: &str) -> String {
+        match self {
+            Self::None => text.to_string(),
+            Self::Nfc => text.nfc().collect(),
+            Self::Nfkc => text.nfkc().collect(),
+        }
+    }
+}
 
-    // Create semaphore for concurrent control
-    let semaphore = Arc::new(Semaphore::new(max_concurrent_batches));
-    let mut handles = Vec::new();
+// How tweets that already exist in the graph are handled
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ImportMode {
+    /// Update existing tweets in place
+    #[default]
+    Merge,
+    /// Only create new tweets, the tweets that already exist are skipped
+    Create,
+    /// Delete existing tweets with their relations and create them again
+    Replace,
+}
 
-    for (batch_idx, chunk) in tweets.chunks(batch_size).enumerate() {
-        let graph_clone = graph.clone();
-        let chunk_vec = chunk.to_vec();
-        let sem_clone = semaphore.clone();
+// How the users mentioned by a tweet are stored
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum UserMentionStrategy {
+    /// Only the `user_mentions` array of the tweets
+    Array,
+    /// Only MENTIONS relations, the array is removed once they are built. Mentions
+    /// of users that are not in the graph yet are lost
+    Nodes,
+    /// The array and the MENTIONS relations
+    #[default]
+    Both,
+}
 
-        let handle = tokio::spawn(async move {
-            let _permit = sem_clone.acquire().await.unwrap();
-            let batch = prepare_batch_parameters(chunk_vec);
-
-            // Define retry configuration
-            let backoff = ExponentialBackoff {
-                initial_interval: Duration::from_millis(100),
-                max_interval: Duration::from_secs(10),
-                multiplier: 2.0,
-                max_elapsed_time: Some(Duration::from_secs(60)), // Max 1 minute of retries
-                ..ExponentialBackoff::default()
-            };
+// Tweet properties that can be imported, named like the node properties.
+// The id and the author of a tweet are always imported
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, ValueEnum)]
+#[value(rename_all = "snake_case")]
+pub enum TweetField {
+    Id,
+    Text,
+    CreatedAt,
+    ReplyTo,
+    ReplyToUserId,
+    Lang,
+    Hashtags,
+    UserMentions,
+    Cashtags,
+    // The expanded URLs of the links, or the t.co links when not expanded
+    Urls,
+    EditHistory,
+    RetweetCount,
+    FavoriteCount,
+    Location,
+    // SCOPED_TO relations to the places of `scopes.place_ids`
+    Scopes,
+}
 
-            // Execute with retry logic
-            match backoff::future::retry(backoff, || async {
-                match run_insert_with_txn(&graph_clone, batch.clone()).await {
-                    Ok(_) => Ok(()),
-                    Err(e) => {
-                        // Check if error is a deadlock error
-                        if is_deadlock_error(&e) {
-                            println!("Deadlock detected in batch {}, will retry", batch_idx);
-                            Err(BackoffError::transient(e))
-                        } else {
-                            // For other errors, don't retry
-                            Err(BackoffError::permanent(e))
-                        }
+impl TweetField {
+    fn property(self) -> String {
+        self.to_possible_value().unwrap().get_name().to_owned()
+    }
+
+    // Keys of the query parameters of a tweet holding this field
+    fn param_keys(self) -> Vec<String> {
+        match self {
+            TweetField::Location => vec!["longitude".to_owned(), "latitude".to_owned()],
+            TweetField::Scopes => vec!["scopePlaceIds".to_owned()],
+            field => vec![field.property()],
+        }
+    }
+}
+
+// User properties that can be imported, named like the node properties.
+// The id of a user is always imported
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, ValueEnum)]
+#[value(rename_all = "snake_case")]
+pub enum UserField {
+    Id,
+    Name,
+    Location,
+    Verified,
+    FollowersCount,
+    FriendsCount,
+    ListedCount,
+    FavouritesCount,
+    StatusesCount,
+    CreatedAt,
+    UtcOffset,
+    Protected,
+}
+
+impl UserField {
+    fn property(self) -> String {
+        self.to_possible_value().unwrap().get_name().to_owned()
+    }
+}
+
+// The properties written to the graph, all of them by default
+#[derive(Debug, Clone)]
+pub struct FieldSet {
+    pub tweet_fields: HashSet<TweetField>,
+    pub user_fields: HashSet<UserField>,
+}
+
+impl Default for FieldSet {
+    fn default() -> Self {
+        Self {
+            tweet_fields: TweetField::value_variants().iter().copied().collect(),
+            user_fields: UserField::value_variants().iter().copied().collect(),
+        }
+    }
+}
+
+impl FieldSet {
+    // Selected fields in declaration order, so that the generated queries are
+    // the same from one batch to the next
+    fn tweet_fields(&self) -> impl Iterator<Item = TweetField> + '_ {
+        TweetField::value_variants()
+            .iter()
+            .copied()
+            .filter(|field| *field != TweetField::Id && self.tweet_fields.contains(field))
+    }
+
+    fn user_fields(&self) -> impl Iterator<Item = UserField> + '_ {
+        UserField::value_variants()
+            .iter()
+            .copied()
+            .filter(|field| *field != UserField::Id && self.user_fields.contains(field))
+    }
+}
+
+// Settings applied to every batch sent by insert_new_tweets
+#[derive(Debug, Default, Clone)]
+pub struct InsertOptions {
+    pub text_normalization: UnicodeNormForm,
+    pub import_mode: ImportMode,
+    pub retry: RetryConfig,
+    pub fields: FieldSet,
+    // Store the unmapped tweet fields in `t.extra_json`
+    #[cfg(feature = "extra_fields")]
+    pub preserve_extra_fields: bool,
+    // Only plan the queries with EXPLAIN and roll the transactions back
+    pub dry_run_db: bool,
+    // Recorded in `t.import_runs` for purge_import
+    pub run_id: Option<String>,
+}
+
+// Tuning of the backoff used when a batch hits a transient error
+#[derive(Debug, Clone, Copy)]
+pub struct RetryConfig {
+    // Each delay is picked at random within +/- this share of the interval.
+    // Without it the 8 concurrent batches that hit the same deadlock would
+    // all retry at the same moment and deadlock again (the thundering herd
+    // described in the "Addressing Cascading Failures" chapter of the Google SRE Book)
+    pub randomization_factor: f64,
+    // Retries allowed per kind of error, on top of the time limit of the backoff
+    pub max_retries: Option<u32>,
+    // A batch without an answer after this long, e.g. during a long GC pause
+    // of the server, is abandoned and retried like a network failure so that
+    // it does not hold its semaphore permit forever
+    pub bolt_timeout: Duration,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            randomization_factor: 0.5,
+            max_retries: None,
+            bolt_timeout: Duration::from_secs(120),
+        }
+    }
+}
+
+// Gives up after `max_retries` delays, or when the inner backoff does
+struct LimitedBackoff<B> {
+    inner: B,
+    retries: u32,
+    max_retries: Option<u32>,
+}
+
+impl<B: Backoff> Backoff for LimitedBackoff<B> {
+    fn next_backoff(&mut self) -> Option<Duration> {
+        if self.max_retries.is_some_and(|max| self.retries >= max) {
+            return None;
+        }
+        self.retries += 1;
+        self.inner.next_backoff()
+    }
+
+    fn reset(&mut self) {
+        self.retries = 0;
+        self.inner.reset();
+    }
+}
+
+// Query parameters for one insert transaction
+#[derive(Debug, Clone)]
+struct BatchParameters {
+    tweets: Vec<HashMap<String, neo4rs::BoltType>>,
+    users: Vec<HashMap<String, neo4rs::BoltType>>,
+}
+
+// Totals of the graph after an import
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct GraphStats {
+    pub tweets: u64,
+    pub users: u64,
+    pub replies_to: u64,
+    pub mentions: u64,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ConsistencyReport {
+    pub dangling_replies: u64,
+    pub tweets_without_author: u64,
+    pub users_without_tweets: u64,
+}
+
+impl ConsistencyReport {
+    pub fn is_consistent(&self) -> bool {
+        self.dangling_replies == 0
+            && self.tweets_without_author == 0
+            && self.users_without_tweets == 0
+    }
+}
+
+// Expected duration of an import, +/- `margin` at 95% confidence
+#[derive(Debug, Clone, Copy)]
+pub struct ImportEstimate {
+    pub expected: Duration,
+    pub margin: Duration,
+}
+
+// A constraint or index as listed by SHOW CONSTRAINTS / SHOW INDEXES
+#[derive(Debug, Clone, Serialize)]
+pub struct SchemaItem {
+    pub name: String,
+    pub kind: String,
+    pub labels: Vec<String>,
+    pub properties: Vec<String>,
+    // Only known for indexes, e.g. ONLINE or POPULATING
+    pub state: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SchemaReport {
+    pub constraints: Vec<SchemaItem>,
+    pub indexes: Vec<SchemaItem>,
+    pub labels: Vec<String>,
+    pub relationship_types: Vec<String>,
+}
+
+impl fmt::Display for SchemaItem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} on :{}({})",
+            self.name,
+            self.kind,
+            self.labels.join(":"),
+            self.properties.join(", ")
+        )?;
+        if let Some(state) = &self.state {
+            write!(f, " [{}]", state)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for SchemaReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Constraints:")?;
+        for constraint in &self.constraints {
+            writeln!(f, "  {}", constraint)?;
+        }
+        writeln!(f, "Indexes:")?;
+        for index in &self.indexes {
+            writeln!(f, "  {}", index)?;
+        }
+        writeln!(f, "{:<20}{}", "Labels:", self.labels.join(", "))?;
+        write!(
+            f,
+            "{:<20}{}",
+            "Relationship types:",
+            self.relationship_types.join(", ")
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SemVer {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl SemVer {
+    // Used when the server version cannot be detected
+    const DEFAULT: SemVer = SemVer::new(4, 4, 0);
+
+    pub const fn new(major: u32, minor: u32, patch: u32) -> Self {
+        Self {
+            major,
+            minor,
+            patch,
+        }
+    }
+
+    // Parses versions like "5.12.0" or "4.4.26-enterprise"
+    fn parse(version: &str) -> Option<Self> {
+        let mut parts = version
+            .split(|c: char| !c.is_ascii_digit())
+            .map(|part| part.parse::<u32>());
+        let major = parts.next()?.ok()?;
+        let minor = parts.next().and_then(Result::ok).unwrap_or(0);
+        let patch = parts.next().and_then(Result::ok).unwrap_or(0);
+        Some(Self::new(major, minor, patch))
+    }
+}
+
+static NEO4J_VERSION: OnceLock<SemVer> = OnceLock::new();
+
+// Set from `--tenant-prefix`, namespaces the labels in TENANT_LABELS so that
+// several imports can share one database
+static TENANT_PREFIX: OnceLock<String> = OnceLock::new();
+
+const TENANT_LABELS: [&str; 7] = [
+    "Tweet",
+    "User",
+    "Airline",
+    "ImportRun",
+    "Place",
+    "Hashtag",
+    "Day",
+];
+
+pub fn set_tenant_prefix(prefix: String) {
+    TENANT_PREFIX
+        .set(prefix)
+        .expect("The tenant prefix is only set once");
+}
+
+// Opens the connection pool, with a hint for the usual reasons it fails
+pub async fn connect(creds: &Credentials) -> Result<Graph, PipelineError> {
+    Graph::new(&creds.uri, &creds.user, &creds.password)
+        .await
+        .map_err(|e| {
+            let hint = match &e {
+                neo4rs::Error::AuthenticationError(_) => {
+                    "Authentication failed: check user/password".to_owned()
+                }
+                neo4rs::Error::IOError { detail } => match detail.kind() {
+                    io::ErrorKind::ConnectionRefused => {
+                        format!("Connection refused: is Neo4j running on {}?", creds.uri)
                     }
+                    io::ErrorKind::TimedOut => {
+                        format!("Connection timed out: is {} reachable from here?", creds.uri)
+                    }
+                    _ => format!("Network error while connecting to {}: {}", creds.uri, detail),
+                },
+                neo4rs::Error::UrlParseError(_) | neo4rs::Error::UnsupportedScheme(_) => {
+                    format!(
+                        "Invalid URI {}: expected bolt://, neo4j:// or their +s variants",
+                        creds.uri
+                    )
                 }
-            })
-            .await
-            {
-                Ok(_) => println!("Batch {} completed successfully", batch_idx),
-                Err(e) => eprintln!(
-                    "Failed to process batch {} after all retries: {:?}",
-                    batch_idx, e
+                neo4rs::Error::InvalidDnsName(_) => {
+                    format!("Unknown host in {}: check the host name", creds.uri)
+                }
+                // The server closed the connection during the handshake, which
+                // is what a TLS server does with a plain bolt:// client
+                neo4rs::Error::ConnectionError => format!(
+                    "Connection closed by {}: check that bolt:// or bolt+s:// matches the TLS setting of the server",
+                    creds.uri
                 ),
-            }
-        });
+                // Wrong credentials are reported by the server itself
+                _ if e.to_string().contains("Unauthorized") => {
+                    "Authentication failed: check user/password".to_owned()
+                }
+                _ => return PipelineError::Database(e),
+            };
+            PipelineError::Connection(hint)
+        })
+}
 
-        handles.push(handle);
-    }
+// Every query of this module goes through here, so the labels get the tenant
+// prefix when the query is generated
+fn query(cypher: &str) -> neo4rs::Query {
+    neo4rs::query(&tenant_labels(cypher))
+}
 
-    // Wait for all batches to complete
-    future::join_all(handles).await;
+// Same as `query`, for the functions that run through a GraphClient
+fn statement(cypher: &str) -> Statement {
+    Statement::new(tenant_labels(cypher))
 }
 
-// Helper function to detect if an error is a deadlock error
-fn is_deadlock_error(error: &neo4rs::Error) -> bool {
-    // Neo4j deadlock errors typically contain specific codes or text
-    // This is a common pattern, adjust based on actual error details
-    let error_string = format!("{:?}", error);
-    error_string.contains("DeadlockDetected")
-        || error_string.contains("TransactionTerminatedException")
-        || error_string.contains("concurrent access")
-        || error_string.contains("deadlock")
+fn tenant_labels(cypher: &str) -> String {
+    let Some(prefix) = TENANT_PREFIX.get() else {
+        return cypher.to_owned();
+    };
+    TENANT_LABELS
+        .iter()
+        .fold(cypher.to_owned(), |cypher, label| {
+            cypher.replace(&format!(":{}", label), &format!(":{}_{}", prefix, label))
+        })
 }
 
-// Separated transaction execution function for retry logic
-async fn run_insert_with_txn(
-    graph: &Graph,
-    batch: Vec<HashMap<String, neo4rs::BoltType>>,
-) -> Result<(), neo4rs::Error> {
-    let mut txn = graph.start_txn().await?;
+struct UniqueConstraint {
+    name: &'static str,
+    label: &'static str,
+    property: &'static str,
+}
 
-    // Run the query
-    txn.run(
-        query(
-            "
-            UNWIND $batch AS tweet
-            MERGE (t:Tweet {id: tweet.id})
-            SET 
-                t.text = tweet.text,
-                t.created_at = tweet.created_at,
-                t.reply_to = tweet.reply_to,
-                t.lang = tweet.lang,
-                t.hashtags = tweet.hashtags,
-                t.user_mentions = tweet.user_mentions
-            MERGE (u:User {id: tweet.userId})
-            ON CREATE SET 
-                u.name = tweet.userName,
-                u.location = tweet.userLocation,
-                u.verified = tweet.userVerified,
-                u.followers_count = tweet.userFollowersCount,
-                u.friends_count = tweet.userFriendsCount,
-                u.listed_count = tweet.userListedCount,
-                u.favourites_count = tweet.userFavouritesCount, 
-                u.statuses_count = tweet.userStatusesCount,
-                u.created_at = tweet.userCreatedAt,
-                u.utc_offset = tweet.userUtcOffset
-            CREATE (t)-[:POSTED_BY]->(u)
-            ", // listed_count -> on how many lists they are
-               // favourites_count -> how many tweets they liked in lifetime
-               // statuses_count -> how many tweets they posted
-        )
-        .param("batch", batch),
+// Constraints created by prepare_database
+const UNIQUE_CONSTRAINTS: [UniqueConstraint; 3] = [
+    UniqueConstraint {
+        name: "user_id_unique",
+        label: "User",
+        property: "id",
+    },
+    UniqueConstraint {
+        name: "tweet_id_unique",
+        label: "Tweet",
+        property: "id",
+    },
+    UniqueConstraint {
+        name: "place_id_unique",
+        label: "Place",
+        property: "id",
+    },
+];
+
+struct PropertyIndex {
+    name: &'static str,
+    label: &'static str,
+    property: &'static str,
+}
+
+// Indexes created by prepare_database
+const PROPERTY_INDEXES: [PropertyIndex; 1] = [PropertyIndex {
+    name: "tweet_reply_to_user_id",
+    label: "Tweet",
+    property: "reply_to_user_id",
+}];
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct InsertStats {
+    pub batches_ok: usize,
+    pub batches_failed: usize,
+    pub tweets_attempted: usize,
+    pub tweets_inserted: usize,
+    pub batches_skipped_duplicate: usize,
+    pub tweets_skipped_duplicate: usize,
+    // Batch attempts repeated after a transient error
+    pub retries: u64,
+}
+
+impl AddAssign for InsertStats {
+    fn add_assign(&mut self, other: Self) {
+        self.batches_ok += other.batches_ok;
+        self.batches_failed += other.batches_failed;
+        self.tweets_attempted += other.tweets_attempted;
+        self.tweets_inserted += other.tweets_inserted;
+        self.batches_skipped_duplicate += other.batches_skipped_duplicate;
+        self.tweets_skipped_duplicate += other.tweets_skipped_duplicate;
+        self.retries += other.retries;
+    }
+}
+
+pub async fn prepare_database(
+    creds: Arc<Credentials>,
+    constraint_wait: Duration,
+) -> Result<(), PipelineError> {
+    let version = neo4j_version(creds.clone()).await;
+    let graph = connect(&creds).await?;
+    create_schema(&graph, version, constraint_wait).await?;
+    Ok(())
+}
+
+// The constraints first, then the indexes, then waits for the constraints
+async fn create_schema(
+    graph: &impl GraphClient,
+    version: SemVer,
+    constraint_wait: Duration,
+) -> Result<(), neo4rs::Error> {
+    // Run this BEFORE starting any imports to ensure uniqueness of users and tweets.
+    // Schema changes cannot share a transaction, so every constraint is created
+    // in its own auto-committed one
+    future::try_join_all(
+        UNIQUE_CONSTRAINTS
+            .iter()
+            .map(|constraint| graph.run(statement(&constraint.create_ddl(version)))),
+    )
+    .await?;
+    future::try_join_all(
+        PROPERTY_INDEXES
+            .iter()
+            .map(|index| graph.run(statement(&index.create_ddl(version)))),
     )
     .await?;
 
-    // Commit the transaction
-    txn.commit().await?;
+    // Wait for the constraints to be applied, up to `constraint_wait`
+    println!("Waiting for the constraints to be applied...");
+    let names: Vec<String> = UNIQUE_CONSTRAINTS
+        .iter()
+        .map(UniqueConstraint::name)
+        .collect();
+    let started = tokio::time::Instant::now();
+    loop {
+        let applied = graph
+            .fetch_count(
+                statement(
+                    "SHOW CONSTRAINTS YIELD name WHERE name IN $names RETURN count(*) AS count",
+                )
+                .param("names", names.clone()),
+            )
+            .await?;
+        if applied as usize == names.len() {
+            println!("Constraints applied after {:?}", started.elapsed());
+            break;
+        }
+        if started.elapsed() >= constraint_wait {
+            eprintln!(
+                "Warning: only {} of {} constraints applied after {:?}",
+                applied,
+                names.len(),
+                constraint_wait
+            );
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
 
     Ok(())
 }
 
-pub async fn add_replies_to_relation(creds: Credentials) -> Result<(), neo4rs::Error> {
-    println!("Linking tweets together...");
-    let graph = Graph::new(creds.uri, creds.user, creds.password)
-        .await
-        .unwrap();
-
-    let mut txn = graph.start_txn().await?;
-    txn.run(query(
-        "
-        CALL apoc.periodic.iterate(
-          '
-          MATCH (t1:Tweet)
-          WHERE t1.reply_to IS NOT NULL
-          RETURN t1
-          ',
-          '
-          MATCH (t2:Tweet {id: t1.reply_to})
-          MERGE (t1)-[:REPLIES_TO]->(t2)
-          ',
-          {batchSize: 10000, parallel: false}
-        );
-        ",
-    ))
-    .await
-    .unwrap();
+// Polls the running transactions until none but our own remains or `timeout`
+// passes, returns whether the database went idle. Needs Neo4j 4.4 or later
+pub async fn wait_for_idle(
+    creds: Arc<Credentials>,
+    timeout: Duration,
+) -> Result<bool, PipelineError> {
+    let graph = connect(&creds).await?;
+    let started = tokio::time::Instant::now();
+    loop {
+        let active = fetch_count(
+            &graph,
+            "SHOW TRANSACTIONS YIELD currentQuery
+            WHERE NOT currentQuery STARTS WITH 'SHOW TRANSACTIONS'
+            RETURN count(*) AS count",
+        )
+        .await?;
+        if active == 0 {
+            return Ok(true);
+        }
+        if started.elapsed() >= timeout {
+            return Ok(false);
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+}
 
-    txn.commit().await?;
+pub async fn detect_neo4j_version(creds: Arc<Credentials>) -> Result<SemVer, PipelineError> {
+    let graph = connect(&creds).await?;
+    let mut result = graph
+        .execute(query(
+            "CALL dbms.components() YIELD versions RETURN versions[0] AS version",
+        ))
+        .await?;
 
-    Ok(())
+    let version = match result.next().await? {
+        Some(row) => row.get::<String>("version").unwrap_or_default(),
+        None => String::new(),
+    };
+    SemVer::parse(&version).ok_or_else(|| {
+        PipelineError::UnexpectedResult(format!("unrecognized Neo4j version {:?}", version))
+    })
 }
 
-pub async fn add_user_mention_relation(creds: Credentials) -> Result<(), neo4rs::Error> {
-    println!("Adding user mentions...");
-    let graph = Graph::new(creds.uri, creds.user, creds.password)
-        .await
-        .unwrap();
+// Detects the server version once and reuses it for all later DDL statements
+async fn neo4j_version(creds: Arc<Credentials>) -> SemVer {
+    if let Some(version) = NEO4J_VERSION.get() {
+        return *version;
+    }
 
-    let mut txn = graph.start_txn().await?;
-    txn.run(query(
-        "
-        CALL apoc.periodic.iterate(
-          '
-          match (t: Tweet) with t, 
-          t.user_mentions as m UNWIND m as uid 
-          match (u: User {id: uid}) return t, u
-          ',
-          '
-          MERGE (t)-[:MENTIONS]->(u)
-          ',
-          {batchSize: 10000, parallel: false}
-        );
-        ",
-    ))
-    .await
-    .unwrap();
+    let version = match detect_neo4j_version(creds).await {
+        Ok(version) => version,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("Could not detect the Neo4j version, assuming 4.x");
+            SemVer::DEFAULT
+        }
+    };
+    *NEO4J_VERSION.get_or_init(|| version)
+}
 
-    txn.commit().await?;
+// Drops everything prepare_database creates, the first step of a clean re-import
+pub async fn drop_all_pipeline_managed_constraints_and_indexes(
+    creds: Arc<Credentials>,
+) -> Result<(), PipelineError> {
+    let version = neo4j_version(creds.clone()).await;
+    let graph = connect(&creds).await?;
+
+    for constraint in &UNIQUE_CONSTRAINTS {
+        println!("Dropping constraint {}", constraint.name());
+        graph.run(query(&constraint.drop_ddl(version))).await?;
+    }
+    for index in &PROPERTY_INDEXES {
+        println!("Dropping index {}", index.name());
+        graph.run(query(&index.drop_ddl(version))).await?;
+    }
 
     Ok(())
 }
 
-pub async fn add_airline_labels(creds: Credentials) -> Result<(), neo4rs::Error> {
-    println!("Adding airline labels...");
-    let graph = Graph::new(creds.uri, creds.user, creds.password)
-        .await
-        .unwrap();
+impl UniqueConstraint {
+    fn name(&self) -> String {
+        tenant_name(self.name)
+    }
 
-    let mut txn = graph.start_txn().await?;
-    let query = format!(
-        "
-    WITH {:?} AS ids
-    MATCH (n:User)
-    WHERE n.id IN ids
-    SET n:Airline
-        ",
-        AIRLINE_IDS
-    );
+    fn create_ddl(&self, version: SemVer) -> String {
+        if version >= SemVer::new(4, 4, 0) {
+            format!(
+                "CREATE CONSTRAINT {} IF NOT EXISTS FOR (n:{}) REQUIRE n.{} IS UNIQUE",
+                self.name(),
+                self.label,
+                self.property
+            )
+        } else if version >= SemVer::new(4, 0, 0) {
+            format!(
+                "CREATE CONSTRAINT {} IF NOT EXISTS ON (n:{}) ASSERT n.{} IS UNIQUE",
+                self.name(),
+                self.label,
+                self.property
+            )
+        } else {
+            // 3.x has neither names nor IF NOT EXISTS, but recreating an
+            // identical constraint is a no-op
+            format!(
+                "CREATE CONSTRAINT ON (n:{}) ASSERT n.{} IS UNIQUE",
+                self.label, self.property
+            )
+        }
+    }
 
-    txn.run(neo4rs::query(&query)).await.unwrap();
+    fn drop_ddl(&self, version: SemVer) -> String {
+        if version >= SemVer::new(4, 0, 0) {
+            format!("DROP CONSTRAINT {} IF EXISTS", self.name())
+        } else {
+            format!(
+                "DROP CONSTRAINT ON (n:{}) ASSERT n.{} IS UNIQUE",
+                self.label, self.property
+            )
+        }
+    }
+}
 
-    txn.commit().await?;
+impl PropertyIndex {
+    fn name(&self) -> String {
+        tenant_name(self.name)
+    }
 
-    Ok(())
+    fn create_ddl(&self, version: SemVer) -> String {
+        if version >= SemVer::new(4, 1, 0) {
+            format!(
+                "CREATE INDEX {} IF NOT EXISTS FOR (n:{}) ON (n.{})",
+                self.name(),
+                self.label,
+                self.property
+            )
+        } else {
+            format!("CREATE INDEX ON :{}({})", self.label, self.property)
+        }
+    }
+
+    fn drop_ddl(&self, version: SemVer) -> String {
+        if version >= SemVer::new(4, 1, 0) {
+            format!("DROP INDEX {} IF EXISTS", self.name())
+        } else {
+            format!("DROP INDEX ON :{}({})", self.label, self.property)
+        }
+    }
 }
 
-fn prepare_batch_parameters(chunk_vec: Vec<json::Tweet>) -> Vec<HashMap<String, neo4rs::BoltType>> {
-    // Build batch parameters
-    let batch: Vec<HashMap<String, neo4rs::BoltType>> = chunk_vec
-        .iter()
-        .map(|tweet| {
-            let mut tweet_map = HashMap::new();
+// Constraint and index names are unique in the whole database, so they are
+// namespaced like the labels
+fn tenant_name(name: &str) -> String {
+    match TENANT_PREFIX.get() {
+        Some(prefix) => format!("{}_{}", prefix, name),
+        None => name.to_owned(),
+    }
+}
 
-            // Tweet fields
-            tweet_map.insert("id".to_string(), tweet.id_str.clone().into());
-            tweet_map.insert("text".to_string(), tweet.text.clone().into());
-            tweet_map.insert(
-                "created_at".to_string(),
-                tweet.created_at.to_rfc3339().into(),
-            );
-            tweet_map.insert("reply_to".to_string(), tweet.reply_to.clone().into());
-            tweet_map.insert("lang".to_string(), tweet.lang.clone().into());
-            tweet_map.insert(
-                "hashtags".to_string(),
-                tweet.entities.hashtags.clone().into(),
-            );
-            tweet_map.insert(
-                "user_mentions".to_string(),
-                tweet.entities.user_mentions.clone().into(),
-            );
+pub async fn insert_new_tweets(
+    creds: Arc<Credentials>,
+    tweets: Vec<json::Tweet>,
+    options: &InsertOptions,
+) -> Result<InsertStats, PipelineError> {
+    let graph = connect(&creds).await?;
 
-            // User fields
-            tweet_map.insert("userId".to_string(), tweet.user.id_str.clone().into());
-            tweet_map.insert(
-                "userName".to_string(),
-                tweet.user.screen_name.clone().into(),
-            );
-            tweet_map.insert(
-                "userLocation".to_string(),
-                tweet.user.location.clone().into(),
-            );
-            tweet_map.insert("userVerified".to_string(), tweet.user.verified.into());
-            tweet_map.insert(
-                "userFollowersCount".to_string(),
-                tweet.user.followers_count.into(),
-            );
-            tweet_map.insert(
-                "userFriendsCount".to_string(),
-                tweet.user.friends_count.into(),
-            );
-            tweet_map.insert(
-                "userListedCount".to_string(),
-                tweet.user.listed_count.into(),
-            );
-            tweet_map.insert(
-                "userFavouritesCount".to_string(),
-                tweet.user.favourites_count.into(),
-            );
-            tweet_map.insert(
-                "userStatusesCount".to_string(),
-                tweet.user.statuses_count.into(),
-            );
-            tweet_map.insert(
-                "userCreatedAt".to_string(),
-                tweet.user.created_at.to_rfc3339().into(),
-            );
-            tweet_map.insert("userUtcOffset".to_string(), tweet.user.utc_offset.into());
+    let batch_size = 500; // How many nodes per transaction
+    let max_concurrent_batches = 8; // Limit concurrent transactions
+
+    // Create semaphore for concurrent control
+    let semaphore = Arc::new(Semaphore::new(max_concurrent_batches));
+    let retry_count = Arc::new(AtomicU64::new(0));
+    let mut handles = Vec::new();
+
+    for (batch_idx, chunk) in tweets.chunks(batch_size).enumerate() {
+        let graph_clone = graph.clone();
+        let chunk_vec = chunk.to_vec();
+        let sem_clone = semaphore.clone();
+        let options = options.clone();
+        let retry_count = retry_count.clone();
+
+        let handle = tokio::spawn(async move {
+            let _permit = sem_clone.acquire().await.unwrap();
+            let batch = prepare_batch_parameters(chunk_vec, &options);
+
+            insert_batch_with_retry(&graph_clone, batch, batch_idx, &options, &retry_count).await
+        });
+
+        handles.push((handle, chunk.len()));
+    }
+
+    // Wait for all batches to complete
+    let mut stats = InsertStats {
+        tweets_attempted: tweets.len(),
+        ..Default::default()
+    };
+    let (handles, batch_sizes): (Vec<_>, Vec<_>) = handles.into_iter().unzip();
+    for (result, batch_size) in future::join_all(handles).await.into_iter().zip(batch_sizes) {
+        match result {
+            Ok(BatchOutcome::Inserted(inserted)) => {
+                stats.batches_ok += 1;
+                stats.tweets_inserted += inserted;
+                // Only `create` skips the tweets that already exist
+                if inserted < batch_size {
+                    stats.batches_skipped_duplicate += 1;
+                    stats.tweets_skipped_duplicate += batch_size - inserted;
+                }
+            }
+            _ => stats.batches_failed += 1,
+        }
+    }
+    stats.retries = retry_count.load(Ordering::Relaxed);
+
+    Ok(stats)
+}
+
+enum BatchOutcome {
+    // With the number of tweets written, lower than the batch size when the
+    // import mode is `create` and some tweets already exist
+    Inserted(usize),
+    Failed,
+}
+
+// Times the insertion of a sample of the tweets into throwaway
+// _PipelineCalibration nodes and extrapolates to `total_tweets`. The sample is
+// inserted one batch at a time, so the estimate does not account for the
+// concurrent batches of insert_new_tweets and errs on the slow side
+pub async fn estimate_import_time(
+    creds: Arc<Credentials>,
+    sample: Vec<json::Tweet>,
+    total_tweets: usize,
+    options: &InsertOptions,
+) -> Result<ImportEstimate, PipelineError> {
+    const CALIBRATION_BATCHES: usize = 5;
+
+    let graph = connect(&creds).await?;
+    let batch_size = sample.len().div_ceil(CALIBRATION_BATCHES).max(1);
+
+    // Seconds per tweet measured on each batch
+    let mut rates = Vec::new();
+    for chunk in sample.chunks(batch_size) {
+        let batch = prepare_batch_parameters(chunk.to_vec(), options);
+        let started = tokio::time::Instant::now();
+        graph
+            .run(
+                query(
+                    "
+                    UNWIND $tweets AS tweet
+                    CREATE (c:_PipelineCalibration)
+                    SET c += tweet
+                    ",
+                )
+                .param("tweets", batch.tweets),
+            )
+            .await?;
+        rates.push(started.elapsed().as_secs_f64() / chunk.len() as f64);
+    }
+    graph
+        .run(query("MATCH (c:_PipelineCalibration) DETACH DELETE c"))
+        .await?;
+
+    if rates.is_empty() {
+        return Err(PipelineError::UnexpectedResult(
+            "no tweets to calibrate with".to_string(),
+        ));
+    }
+    let n = rates.len() as f64;
+    let mean = rates.iter().sum::<f64>() / n;
+    let variance = if rates.len() > 1 {
+        rates.iter().map(|rate| (rate - mean).powi(2)).sum::<f64>() / (n - 1.)
+    } else {
+        0.
+    };
+    let margin = 1.96 * variance.sqrt() / n.sqrt();
+
+    Ok(ImportEstimate {
+        expected: Duration::from_secs_f64(mean * total_tweets as f64),
+        margin: Duration::from_secs_f64(margin * total_tweets as f64),
+    })
+}
+
+// Runs one batch, retrying transient errors with a backoff chosen per error kind
+async fn insert_batch_with_retry(
+    graph: &impl GraphClient,
+    batch: BatchParameters,
+    batch_idx: usize,
+    options: &InsertOptions,
+    retry_count: &AtomicU64,
+) -> BatchOutcome {
+    let mut backoffs: HashMap<TransientErrorKind, LimitedBackoff<ExponentialBackoff>> =
+        HashMap::new();
+
+    loop {
+        let txn_id = Uuid::new_v4();
+        let insert = run_insert_with_txn(graph, batch.clone(), txn_id, options);
+        let (kind, error) = match tokio::time::timeout(options.retry.bolt_timeout, insert).await {
+            Ok(Ok(inserted)) => {
+                println!("Batch {} completed successfully", batch_idx);
+                return BatchOutcome::Inserted(inserted);
+            }
+            Ok(Err(error)) => {
+                // For non-transient errors, don't retry
+                let Some(kind) = classify_transient_error(&error, options.import_mode) else {
+                    eprintln!("Failed to process batch {}: {:?}", batch_idx, error);
+                    return BatchOutcome::Failed;
+                };
+                (kind, format!("{:?}", error))
+            }
+            Err(_) => (
+                TransientErrorKind::Timeout,
+                format!("no answer within {:?}", options.retry.bolt_timeout),
+            ),
+        };
+
+        let backoff = backoffs.entry(kind).or_insert_with(|| LimitedBackoff {
+            inner: kind.backoff(&options.retry),
+            retries: 0,
+            max_retries: options.retry.max_retries,
+        });
+        match backoff.next_backoff() {
+            Some(delay) => {
+                retry_count.fetch_add(1, Ordering::Relaxed);
+                println!(
+                    "{:?} error in batch {} (txn {}), will retry in {:?}",
+                    kind, batch_idx, txn_id, delay
+                );
+                tokio::time::sleep(delay).await;
+            }
+            None => {
+                eprintln!(
+                    "Failed to process batch {} after all retries: {}",
+                    batch_idx, error
+                );
+                return BatchOutcome::Failed;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum TransientErrorKind {
+    Deadlock,
+    // Often a server shutting down for maintenance, so worth waiting longer for
+    TransactionTerminated,
+    NetworkFailure,
+    // The server did not answer within the bolt timeout
+    Timeout,
+    // In `create` mode, another batch created one of the tweets since this
+    // batch checked that they were new. The retry skips it
+    ConcurrentCreate,
+    Unknown,
+}
+
+impl TransientErrorKind {
+    fn backoff(self, retry: &RetryConfig) -> ExponentialBackoff {
+        let (initial_interval, max_interval, max_elapsed_time) = match self {
+            Self::Deadlock | Self::ConcurrentCreate | Self::Unknown => (
+                Duration::from_millis(100),
+                Duration::from_secs(10),
+                Duration::from_secs(60),
+            ),
+            Self::TransactionTerminated => (
+                Duration::from_secs(5),
+                Duration::from_secs(60),
+                Duration::from_secs(300),
+            ),
+            Self::NetworkFailure | Self::Timeout => (
+                Duration::from_secs(1),
+                Duration::from_secs(30),
+                Duration::from_secs(120),
+            ),
+        };
+
+        ExponentialBackoff {
+            initial_interval,
+            current_interval: initial_interval,
+            max_interval,
+            multiplier: 2.0,
+            randomization_factor: retry.randomization_factor,
+            max_elapsed_time: Some(max_elapsed_time),
+            ..ExponentialBackoff::default()
+        }
+    }
+}
+
+// Returns the kind of a retryable error, or None if retrying won't help
+fn classify_transient_error(error: &neo4rs::Error, mode: ImportMode) -> Option<TransientErrorKind> {
+    // Neo4j errors typically contain specific codes or text
+    // This is a common pattern, adjust based on actual error details
+    let error_string = format!("{:?}", error);
+    if mode == ImportMode::Create && error_string.contains("ConstraintValidationFailed") {
+        Some(TransientErrorKind::ConcurrentCreate)
+    } else if error_string.contains("DeadlockDetected")
+        || error_string.contains("concurrent access")
+        || error_string.contains("deadlock")
+    {
+        Some(TransientErrorKind::Deadlock)
+    } else if error_string.contains("TransactionTerminatedException")
+        || error_string.contains("Transaction.Terminated")
+    {
+        Some(TransientErrorKind::TransactionTerminated)
+    } else if error_string.contains("IOError") || error_string.contains("ConnectionError") {
+        Some(TransientErrorKind::NetworkFailure)
+    } else if error_string.contains("TransientError") {
+        Some(TransientErrorKind::Unknown)
+    } else {
+        None
+    }
+}
+
+// Merges user nodes without any tweet, for user metadata dumps. Existing users
+// get the properties of the dump. Returns the number of users written
+pub async fn upsert_users_bulk(
+    creds: Arc<Credentials>,
+    mut users: Vec<json::User>,
+    normalization: UnicodeNormForm,
+) -> Result<u64, PipelineError> {
+    const BATCH_SIZE: usize = 500;
+
+    let graph = connect(&creds).await?;
+    for user in &mut users {
+        user.screen_name = normalization.apply(&user.screen_name);
+    }
+
+    let mut written = 0;
+    for chunk in users.chunks(BATCH_SIZE) {
+        let params: Vec<HashMap<String, neo4rs::BoltType>> =
+            chunk.iter().map(HashMap::from).collect();
+        let mut txn = graph.start_txn().await?;
+        txn.run(
+            query(
+                "
+                UNWIND $users AS user
+                MERGE (u:User {id: user.id})
+                SET u += user
+                ",
+            )
+            .param("users", params),
+        )
+        .await?;
+        txn.commit().await?;
+        written += chunk.len() as u64;
+    }
+    Ok(written)
+}
+
+// Batches with fewer tweets are inlined as Cypher literals, where converting
+// the parameters to Bolt costs more than parsing the longer query
+const INLINE_BATCH_SIZE: usize = 10;
+
+// Separated transaction execution function for retry logic
+async fn run_insert_with_txn(
+    graph: &impl GraphClient,
+    batch: BatchParameters,
+    txn_id: Uuid,
+    options: &InsertOptions,
+) -> Result<usize, neo4rs::Error> {
+    let batch_size = batch.tweets.len();
+
+    // The id is sent as a Cypher comment so it shows up in the Neo4j query log
+    let mut txn_comment = format!("// txn: {}", txn_id);
+    if options.dry_run_db {
+        txn_comment.push_str("\nEXPLAIN");
+    }
+
+    let users_query = statement(&format!(
+        "{}{}",
+        txn_comment,
+        QueryBuilder::new(options).build_users_query()
+    ))
+    .param("users", batch.users);
+
+    let tweets_query = format!(
+        "{}{}",
+        txn_comment,
+        QueryBuilder::new(options).build_insert_query()
+    );
+    let tweets_query = if batch.tweets.len() < INLINE_BATCH_SIZE {
+        Statement::new(inline_tweets(&tweets_query, &batch.tweets))
+    } else {
+        statement(&tweets_query).param("tweets", batch.tweets)
+    };
+    let tweets_query = tweets_query.param("runId", options.run_id.clone());
+
+    let inserted = graph
+        .run_in_txn(
+            vec![users_query, tweets_query],
+            "inserted",
+            options.dry_run_db,
+        )
+        .await?;
+    if options.dry_run_db {
+        // EXPLAIN returns no rows
+        return Ok(batch_size);
+    }
+    Ok(inserted.unwrap_or(0) as usize)
+}
+
+// Writes every node and relationship as Cypher statements that recreate the
+// graph with `cypher-shell --file`. Nodes temporarily get a _BackupNode label
+// and a _backup_id holding their current id, so that the relationships can
+// find their ends; both are removed at the end of the file. Returns the number
+// of statements written
+pub async fn backup_to_cypher_dump(
+    creds: Arc<Credentials>,
+    path: &Path,
+) -> Result<u64, PipelineError> {
+    let graph = connect(&creds).await?;
+    let mut out = io::BufWriter::new(fs::File::create(path)?);
+    let mut statements = 0;
+
+    writeln!(
+        out,
+        "CREATE CONSTRAINT backup_node_id IF NOT EXISTS FOR (n:_BackupNode) REQUIRE n._backup_id IS UNIQUE;"
+    )?;
+    statements += 1;
+
+    let mut nodes = graph
+        .execute(query(
+            "MATCH (n) RETURN id(n) AS id, labels(n) AS labels, properties(n) AS props",
+        ))
+        .await?;
+    while let Some(row) = nodes.next().await? {
+        let id = row.get::<i64>("id").unwrap_or_default();
+        let labels: String = row
+            .get::<Vec<String>>("labels")
+            .unwrap_or_default()
+            .iter()
+            .map(|label| format!(":`{}`", label.replace('`', "``")))
+            .collect();
+        let props = row.get::<neo4rs::BoltMap>("props").unwrap_or_default();
+        writeln!(
+            out,
+            "CREATE (:_BackupNode{} {});",
+            labels,
+            cypher_map(&props, Some(id))
+        )?;
+        statements += 1;
+    }
+
+    let mut relations = graph
+        .execute(query(
+            "MATCH (a)-[r]->(b) RETURN id(a) AS start, id(b) AS end, type(r) AS type, properties(r) AS props",
+        ))
+        .await?;
+    while let Some(row) = relations.next().await? {
+        let props = row.get::<neo4rs::BoltMap>("props").unwrap_or_default();
+        writeln!(
+            out,
+            "MATCH (a:_BackupNode {{_backup_id: {}}}), (b:_BackupNode {{_backup_id: {}}}) MERGE (a)-[:`{}` {}]->(b);",
+            row.get::<i64>("start").unwrap_or_default(),
+            row.get::<i64>("end").unwrap_or_default(),
+            row.get::<String>("type")
+                .unwrap_or_default()
+                .replace('`', "``"),
+            cypher_map(&props, None)
+        )?;
+        statements += 1;
+    }
+
+    writeln!(
+        out,
+        "MATCH (n:_BackupNode) CALL {{ WITH n REMOVE n:_BackupNode, n._backup_id }} IN TRANSACTIONS;"
+    )?;
+    writeln!(out, "DROP CONSTRAINT backup_node_id IF EXISTS;")?;
+    statements += 2;
+
+    out.flush()?;
+    Ok(statements)
+}
+
+// Puts the tweets in the query in place of the `$tweets` parameter. The labels
+// get the tenant prefix before the tweets are inlined, so that the text of a
+// tweet is never rewritten
+fn inline_tweets(cypher: &str, tweets: &[HashMap<String, neo4rs::BoltType>]) -> String {
+    let literals: Vec<String> = tweets.iter().map(tweet_to_cypher_literal).collect();
+    tenant_labels(cypher).replacen("$tweets", &format!("[{}]", literals.join(", ")), 1)
+}
+
+// Map literal of the parameters of a tweet, so that small batches can be
+// inlined in the query instead of being sent as parameters
+fn tweet_to_cypher_literal(tweet: &HashMap<String, neo4rs::BoltType>) -> String {
+    cypher_map_entries(tweet.iter().map(|(key, value)| (key.as_str(), value)), None)
+}
+
+// Map literal of the properties, with the backup id of a node when given
+fn cypher_map(props: &neo4rs::BoltMap, backup_id: Option<i64>) -> String {
+    cypher_map_entries(
+        props
+            .value
+            .iter()
+            .map(|(key, value)| (key.value.as_str(), value)),
+        backup_id,
+    )
+}
+
+// Null values are left out, which reads the same as a missing key. The keys
+// are sorted so that the same map always gives the same literal
+fn cypher_map_entries<'a>(
+    props: impl Iterator<Item = (&'a str, &'a neo4rs::BoltType)>,
+    backup_id: Option<i64>,
+) -> String {
+    let mut props: Vec<_> = props
+        .filter(|(_, value)| !matches!(value, neo4rs::BoltType::Null(_)))
+        .collect();
+    props.sort_by_key(|(key, _)| *key);
+    let mut entries: Vec<String> = props
+        .into_iter()
+        .map(|(key, value)| format!("{}: {}", quote_identifier(key), cypher_literal(value)))
+        .collect();
+    if let Some(id) = backup_id {
+        entries.push(format!("_backup_id: {}", id));
+    }
+    format!("{{{}}}", entries.join(", "))
+}
+
+fn cypher_literal(value: &neo4rs::BoltType) -> String {
+    use neo4rs::BoltType;
+    match value {
+        BoltType::Null(_) => "null".to_owned(),
+        BoltType::String(s) => cypher_string(&s.value),
+        BoltType::Boolean(b) => b.value.to_string(),
+        BoltType::Integer(i) => i.value.to_string(),
+        BoltType::Float(f) => cypher_float(f.value),
+        BoltType::List(list) => format!(
+            "[{}]",
+            list.value
+                .iter()
+                .map(cypher_literal)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        BoltType::Map(map) => cypher_map(map, None),
+        BoltType::Point2D(point) => format!(
+            "point({{srid: {}, x: {}, y: {}}})",
+            point.sr_id.value,
+            cypher_float(point.x.value),
+            cypher_float(point.y.value)
+        ),
+        BoltType::Point3D(point) => format!(
+            "point({{srid: {}, x: {}, y: {}, z: {}}})",
+            point.sr_id.value,
+            cypher_float(point.x.value),
+            cypher_float(point.y.value),
+            cypher_float(point.z.value)
+        ),
+        // The pipeline stores dates as strings, other types are kept readable
+        other => cypher_string(&format!("{:?}", other)),
+    }
+}
+
+// Control characters are escaped, so that a value never breaks the line of
+// its statement
+fn cypher_string(value: &str) -> String {
+    let mut literal = String::with_capacity(value.len() + 2);
+    literal.push('\'');
+    for c in value.chars() {
+        match c {
+            '\\' => literal.push_str("\\\\"),
+            '\'' => literal.push_str("\\'"),
+            '\n' => literal.push_str("\\n"),
+            '\r' => literal.push_str("\\r"),
+            '\t' => literal.push_str("\\t"),
+            c if c.is_control() => literal.push_str(&format!("\\u{:04X}", c as u32)),
+            c => literal.push(c),
+        }
+    }
+    literal.push('\'');
+    literal
+}
+
+// Cypher has no literal for NaN and the infinities, the divisions by zero
+// evaluate to them
+fn cypher_float(value: f64) -> String {
+    if value.is_nan() {
+        "(0.0 / 0.0)".to_owned()
+    } else if value.is_infinite() {
+        format!("({}1.0 / 0.0)", if value < 0.0 { "-" } else { "" })
+    } else {
+        // Debug keeps the decimal point, so the value is read back as a float
+        format!("{:?}", value)
+    }
+}
+
+// Runs a user supplied query as is, without the tenant prefix, and hands every
+// row to `on_row` as a JSON object keyed by column
+pub async fn run_cypher(
+    creds: Arc<Credentials>,
+    cypher: &str,
+    params: serde_json::Map<String, serde_json::Value>,
+    mut on_row: impl FnMut(serde_json::Value),
+) -> Result<(), PipelineError> {
+    let graph = connect(&creds).await?;
+    let mut cypher = neo4rs::query(cypher);
+    for (key, value) in &params {
+        cypher = cypher.param(key, json_to_bolt(value));
+    }
+
+    let mut result = graph.execute(cypher).await?;
+    while let Some(row) = result.next().await? {
+        let columns = row
+            .to::<HashMap<String, neo4rs::BoltType>>()
+            .map_err(|e| PipelineError::UnexpectedResult(e.to_string()))?
+            .into_iter()
+            .map(|(key, value)| (key, bolt_to_json(&value)))
+            .collect();
+        on_row(serde_json::Value::Object(columns));
+    }
+    Ok(())
+}
+
+fn json_to_bolt(value: &serde_json::Value) -> neo4rs::BoltType {
+    use serde_json::Value;
+    match value {
+        Value::Null => neo4rs::BoltType::Null(neo4rs::BoltNull),
+        Value::Bool(b) => (*b).into(),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => i.into(),
+            None => n.as_f64().unwrap_or(f64::NAN).into(),
+        },
+        Value::String(s) => s.clone().into(),
+        Value::Array(values) => values.iter().map(json_to_bolt).collect::<Vec<_>>().into(),
+        Value::Object(map) => map
+            .iter()
+            .map(|(key, value)| (key.clone(), json_to_bolt(value)))
+            .collect::<HashMap<_, _>>()
+            .into(),
+    }
+}
+
+// Nodes, relationships and paths become objects shaped like the ones of the
+// Neo4j HTTP API. Temporal values are written with their debug representation
+fn bolt_to_json(value: &neo4rs::BoltType) -> serde_json::Value {
+    use neo4rs::BoltType;
+    use serde_json::{Value, json};
+
+    fn properties(map: &neo4rs::BoltMap) -> Value {
+        Value::Object(
+            map.value
+                .iter()
+                .map(|(key, value)| (key.value.clone(), bolt_to_json(value)))
+                .collect(),
+        )
+    }
+    fn list(list: &neo4rs::BoltList) -> Value {
+        Value::Array(list.value.iter().map(bolt_to_json).collect())
+    }
+
+    match value {
+        BoltType::Null(_) => Value::Null,
+        BoltType::String(s) => json!(s.value),
+        BoltType::Boolean(b) => json!(b.value),
+        BoltType::Integer(i) => json!(i.value),
+        BoltType::Float(f) => json!(f.value),
+        BoltType::List(l) => list(l),
+        BoltType::Map(m) => properties(m),
+        BoltType::Bytes(b) => json!(b.value.to_vec()),
+        BoltType::Node(node) => json!({
+            "id": node.id.value,
+            "labels": list(&node.labels),
+            "properties": properties(&node.properties),
+        }),
+        BoltType::Relation(rel) => json!({
+            "id": rel.id.value,
+            "type": rel.typ.value,
+            "start": rel.start_node_id.value,
+            "end": rel.end_node_id.value,
+            "properties": properties(&rel.properties),
+        }),
+        BoltType::UnboundedRelation(rel) => json!({
+            "id": rel.id.value,
+            "type": rel.typ.value,
+            "properties": properties(&rel.properties),
+        }),
+        BoltType::Path(path) => json!({
+            "nodes": list(&path.nodes),
+            "relationships": list(&path.rels),
+        }),
+        BoltType::Point2D(point) => json!({
+            "srid": point.sr_id.value,
+            "x": point.x.value,
+            "y": point.y.value,
+        }),
+        BoltType::Point3D(point) => json!({
+            "srid": point.sr_id.value,
+            "x": point.x.value,
+            "y": point.y.value,
+            "z": point.z.value,
+        }),
+        other => json!(format!("{:?}", other)),
+    }
+}
+
+// Lets the relation steps be skipped when no tweet could produce an edge.
+// The LIMIT stops the scan at the first matching tweet
+pub async fn has_tweets_with_mentions(creds: Arc<Credentials>) -> Result<bool, PipelineError> {
+    has_any(
+        creds,
+        "MATCH (t:Tweet) WHERE size(t.user_mentions) > 0
+        WITH t LIMIT 1
+        RETURN count(t) > 0 AS found",
+    )
+    .await
+}
+
+pub async fn has_replies(creds: Arc<Credentials>) -> Result<bool, PipelineError> {
+    has_any(
+        creds,
+        "MATCH (t:Tweet) WHERE t.reply_to IS NOT NULL
+        WITH t LIMIT 1
+        RETURN count(t) > 0 AS found",
+    )
+    .await
+}
+
+// Expects a query returning a boolean `found` column
+async fn has_any(creds: Arc<Credentials>, cypher: &str) -> Result<bool, PipelineError> {
+    let graph = connect(&creds).await?;
+    let mut result = graph.execute(query(cypher)).await?;
+
+    Ok(match result.next().await? {
+        Some(row) => row.get::<bool>("found").unwrap_or(false),
+        None => false,
+    })
+}
+
+pub async fn add_replies_to_relation(creds: Arc<Credentials>) -> Result<u64, PipelineError> {
+    println!("Linking tweets together...");
+    let graph = connect(&creds).await?;
+
+    let before = count_relations(&graph, "Tweet", "REPLIES_TO").await?;
+    let mut txn = graph.start_txn().await?;
+    txn.run(query(
+        "
+        CALL apoc.periodic.iterate(
+          '
+          MATCH (t1:Tweet)
+          WHERE t1.reply_to IS NOT NULL
+          RETURN t1
+          ',
+          '
+          MATCH (t2:Tweet {id: t1.reply_to})
+          MERGE (t1)-[:REPLIES_TO]->(t2)
+          ',
+          {batchSize: 10000, parallel: false}
+        );
+        ",
+    ))
+    .await
+    .unwrap();
+
+    txn.commit().await?;
+
+    let added = count_relations(&graph, "Tweet", "REPLIES_TO")
+        .await?
+        .saturating_sub(before);
+    Ok(added)
+}
+
+// Replies always mention the author of the replied tweet, this edge keeps
+// them apart from the other mentions
+pub async fn add_direct_reply_relation(creds: Arc<Credentials>) -> Result<u64, PipelineError> {
+    println!("Linking replies to the users they reply to...");
+    let graph = connect(&creds).await?;
+
+    let before = count_relations(&graph, "Tweet", "DIRECT_REPLY_TO_USER").await?;
+    let mut txn = graph.start_txn().await?;
+    txn.run(query(
+        "
+        CALL apoc.periodic.iterate(
+          '
+          MATCH (t:Tweet)
+          WHERE t.reply_to_user_id IS NOT NULL
+          RETURN t
+          ',
+          '
+          MATCH (u:User {id: t.reply_to_user_id})
+          MERGE (t)-[:DIRECT_REPLY_TO_USER]->(u)
+          ',
+          {batchSize: 10000, parallel: false}
+        );
+        ",
+    ))
+    .await?;
+
+    txn.commit().await?;
+
+    let added = count_relations(&graph, "Tweet", "DIRECT_REPLY_TO_USER")
+        .await?
+        .saturating_sub(before);
+    Ok(added)
+}
+
+pub async fn add_user_mention_relation(creds: Arc<Credentials>) -> Result<u64, PipelineError> {
+    println!("Adding user mentions...");
+    let graph = connect(&creds).await?;
+
+    let before = count_relations(&graph, "Tweet", "MENTIONS").await?;
+    let mut txn = graph.start_txn().await?;
+    txn.run(query(
+        "
+        CALL apoc.periodic.iterate(
+          '
+          match (t:Tweet) with t,
+          t.user_mentions as m UNWIND m as uid
+          match (u:User {id: uid}) return t, u
+          ',
+          '
+          MERGE (t)-[:MENTIONS]->(u)
+          ',
+          {batchSize: 10000, parallel: false}
+        );
+        ",
+    ))
+    .await
+    .unwrap();
+
+    txn.commit().await?;
+
+    let added = count_relations(&graph, "Tweet", "MENTIONS")
+        .await?
+        .saturating_sub(before);
+    Ok(added)
+}
+
+// Drops the `user_mentions` arrays once the MENTIONS relations are built.
+// Returns the number of tweets updated
+pub async fn remove_user_mention_arrays(creds: Arc<Credentials>) -> Result<u64, PipelineError> {
+    println!("Removing the user mention arrays...");
+    let graph = connect(&creds).await?;
+
+    let mut result = graph
+        .execute(query(
+            "
+            CALL apoc.periodic.iterate(
+              'MATCH (t:Tweet) WHERE t.user_mentions IS NOT NULL RETURN t',
+              'REMOVE t.user_mentions',
+              {batchSize: 10000, parallel: true}
+            ) YIELD total
+            RETURN total AS count
+            ",
+        ))
+        .await?;
+    Ok(match result.next().await? {
+        Some(row) => row.get::<i64>("count").unwrap_or(0) as u64,
+        None => 0,
+    })
+}
+
+pub async fn add_edit_history_relations(creds: Arc<Credentials>) -> Result<u64, PipelineError> {
+    println!("Linking edited tweets to their previous versions...");
+    let graph = connect(&creds).await?;
+
+    let before = count_relations(&graph, "Tweet", "EDITED_FROM").await?;
+    let mut txn = graph.start_txn().await?;
+    // edit_history lists every version of the tweet, oldest first
+    txn.run(query(
+        "
+        CALL apoc.periodic.iterate(
+          '
+          MATCH (t:Tweet)
+          WHERE size(t.edit_history) > 1
+          WITH t, apoc.coll.indexOf(t.edit_history, t.id) AS i
+          WHERE i > 0
+          RETURN t, t.edit_history[i - 1] AS previous_id
+          ',
+          '
+          MATCH (previous:Tweet {id: previous_id})
+          MERGE (t)-[:EDITED_FROM]->(previous)
+          ',
+          {batchSize: 10000, parallel: false}
+        );
+        ",
+    ))
+    .await?;
+
+    txn.run(query(
+        "
+        MATCH (t:Tweet)
+        WHERE t.edit_history IS NOT NULL AND t.id = t.edit_history[0]
+        SET t.is_original = true
+        ",
+    ))
+    .await?;
+
+    txn.commit().await?;
+
+    let added = count_relations(&graph, "Tweet", "EDITED_FROM")
+        .await?
+        .saturating_sub(before);
+    Ok(added)
+}
+
+// Counts the relationships of the given type, used to report what a step added.
+// The start node is labelled so that a tenant only counts its own relationships
+async fn count_relations(
+    graph: &Graph,
+    from_label: &str,
+    rel_type: &str,
+) -> Result<u64, neo4rs::Error> {
+    fetch_count(
+        graph,
+        &format!(
+            "MATCH (:{})-[r:{}]->() RETURN count(r) AS count",
+            from_label, rel_type
+        ),
+    )
+    .await
+}
+
+// Runs a query returning a single `count` column and extracts its value
+async fn fetch_count(graph: &Graph, cypher: &str) -> Result<u64, neo4rs::Error> {
+    let mut result = graph.execute(query(cypher)).await?;
+
+    let count = match result.next().await? {
+        Some(row) => row.get::<i64>("count").unwrap_or(0) as u64,
+        None => 0,
+    };
+    Ok(count)
+}
+
+// Splits created_at into integer year, month and day properties with a
+// (year, month) index, so that date range queries do not compare strings.
+// Returns the number of tweets updated
+pub async fn add_temporal_indices(creds: Arc<Credentials>) -> Result<u64, PipelineError> {
+    println!("Adding temporal properties...");
+    let graph = connect(&creds).await?;
+
+    let mut result = graph
+        .execute(query(
+            "
+            CALL apoc.periodic.iterate(
+              '
+              MATCH (t:Tweet)
+              WHERE t.year IS NULL AND t.created_at IS NOT NULL
+              RETURN t
+              ',
+              '
+              SET t.year = toInteger(substring(t.created_at, 0, 4)),
+                  t.month = toInteger(substring(t.created_at, 5, 2)),
+                  t.day = toInteger(substring(t.created_at, 8, 2))
+              ',
+              {batchSize: 10000, parallel: true}
+            ) YIELD total
+            RETURN total AS count
+            ",
+        ))
+        .await?;
+    let updated = match result.next().await? {
+        Some(row) => row.get::<i64>("count").unwrap_or(0) as u64,
+        None => 0,
+    };
+
+    graph
+        .run(query(&format!(
+            "CREATE INDEX {} IF NOT EXISTS FOR (t:Tweet) ON (t.year, t.month)",
+            tenant_name("tweet_year_month")
+        )))
+        .await?;
+
+    Ok(updated)
+}
+
+// Circular REPLIES_TO chains, up to 100 of them, as the ids of their tweets.
+// Each cycle starts with its smallest tweet id so that it is only returned once
+pub async fn find_circular_reply_chains(
+    creds: Arc<Credentials>,
+) -> Result<Vec<Vec<String>>, PipelineError> {
+    println!("Looking for circular reply chains...");
+    let graph = connect(&creds).await?;
+    let mut result = graph
+        .execute(query(
+            "
+            MATCH path = (t:Tweet)-[:REPLIES_TO*2..50]->(t)
+            WHERE all(n IN nodes(path)[1..-1] WHERE t.id < n.id)
+            RETURN [n IN nodes(path)[..-1] | n.id] AS cycle
+            LIMIT 100
+            ",
+        ))
+        .await?;
+
+    let mut cycles = Vec::new();
+    while let Some(row) = result.next().await? {
+        cycles.push(row.get::<Vec<String>>("cycle").unwrap_or_default());
+    }
+    Ok(cycles)
+}
+
+// Deletes the REPLIES_TO relation from the first to the second tweet of every
+// cycle. Returns the number of relations deleted
+pub async fn break_circular_reply_chains(
+    creds: Arc<Credentials>,
+    cycles: &[Vec<String>],
+) -> Result<u64, PipelineError> {
+    let graph = connect(&creds).await?;
+    let mut result = graph
+        .execute(
+            query(
+                "
+                UNWIND $cycles AS cycle
+                MATCH (:Tweet {id: cycle[0]})-[r:REPLIES_TO]->(:Tweet {id: cycle[1]})
+                DELETE r
+                RETURN count(r) AS count
+                ",
+            )
+            .param("cycles", cycles.to_vec()),
+        )
+        .await?;
+    Ok(match result.next().await? {
+        Some(row) => row.get::<i64>("count").unwrap_or(0) as u64,
+        None => 0,
+    })
+}
+
+// Links the tweets of a graph imported with the `hashtags` array property only
+// to Hashtag nodes through TAGGED_WITH relations. Everything is merged, so the
+// migration can be run again, e.g. after a later import
+pub async fn migrate_hashtag_array_to_nodes(
+    creds: Arc<Credentials>,
+) -> Result<MigrationStats, PipelineError> {
+    println!("Migrating hashtag arrays to Hashtag nodes...");
+    let graph = connect(&creds).await?;
+    graph
+        .run(query(&format!(
+            "CREATE INDEX {} IF NOT EXISTS FOR (h:Hashtag) ON (h.name)",
+            tenant_name("hashtag_name")
+        )))
+        .await?;
+
+    let tags = fetch_count(
+        &graph,
+        "
+        MATCH (t:Tweet) WHERE t.hashtags IS NOT NULL
+        UNWIND t.hashtags AS tag
+        RETURN count(DISTINCT tag) AS count
+        ",
+    )
+    .await?;
+    let count_hashtags = "MATCH (h:Hashtag) RETURN count(h) AS count";
+    let hashtags_before = fetch_count(&graph, count_hashtags).await?;
+    let relations_before = count_relations(&graph, "Tweet", "TAGGED_WITH").await?;
+
+    graph
+        .run(
+            query(
+                "
+                CALL apoc.periodic.iterate(
+                  $tweets,
+                  '
+                  UNWIND t.hashtags AS tag
+                  MERGE (h:Hashtag {name: tag})
+                  MERGE (t)-[:TAGGED_WITH]->(h)
+                  ',
+                  {batchSize: 10000, parallel: false}
+                )
+                ",
+            )
+            .param(
+                "tweets",
+                tenant_labels("MATCH (t:Tweet) WHERE t.hashtags IS NOT NULL RETURN t"),
+            ),
+        )
+        .await?;
+
+    let hashtags_created = fetch_count(&graph, count_hashtags)
+        .await?
+        .saturating_sub(hashtags_before);
+    Ok(MigrationStats {
+        hashtags_created,
+        hashtags_existing: tags.saturating_sub(hashtags_created),
+        relations_created: count_relations(&graph, "Tweet", "TAGGED_WITH")
+            .await?
+            .saturating_sub(relations_before),
+    })
+}
+
+// Links every user to the `top_k` users sharing the most hashtags with them,
+// at least `min_shared_hashtags`, with SIMILAR_TO relations holding the number
+// of shared hashtags. Needs the TAGGED_WITH relations of
+// migrate_hashtag_array_to_nodes. Returns the number of relations set
+pub async fn create_user_similarity_graph(
+    creds: Arc<Credentials>,
+    min_shared_hashtags: u32,
+    top_k: u32,
+) -> Result<u64, PipelineError> {
+    println!("Creating the user similarity graph...");
+    let graph = connect(&creds).await?;
+    if count_relations(&graph, "Tweet", "TAGGED_WITH").await? == 0 {
+        return Err(PipelineError::UnexpectedResult(
+            "no TAGGED_WITH relations, run --migrate-hashtags first".to_string(),
+        ));
+    }
+
+    let mut result = graph
+        .execute(
+            query(
+                "
+                CALL apoc.periodic.iterate(
+                  'MATCH (u1:User) RETURN u1',
+                  '
+                  MATCH (u1)<-[:POSTED_BY]-(:Tweet)-[:TAGGED_WITH]->(h:Hashtag)
+                        <-[:TAGGED_WITH]-(:Tweet)-[:POSTED_BY]->(u2:User)
+                  WHERE u1 <> u2
+                  WITH u1, u2, count(DISTINCT h) AS shared
+                  WHERE shared >= $minShared
+                  ORDER BY shared DESC
+                  WITH u1, collect({user: u2, shared: shared})[..$topK] AS similar
+                  UNWIND similar AS s
+                  WITH u1, s.user AS u2, s.shared AS shared
+                  MERGE (u1)-[r:SIMILAR_TO]->(u2)
+                  SET r.shared_hashtags = shared
+                  ',
+                  {batchSize: 1000, parallel: false, params: {minShared: $minShared, topK: $topK}}
+                ) YIELD total
+                RETURN total AS count
+                ",
+            )
+            .param("minShared", min_shared_hashtags as i64)
+            .param("topK", top_k as i64),
+        )
+        .await?;
+    let users = match result.next().await? {
+        Some(row) => row.get::<i64>("count").unwrap_or(0) as u64,
+        None => 0,
+    };
+    println!("Compared the hashtags of {} users", users);
+    Ok(count_relations(&graph, "User", "SIMILAR_TO").await?)
+}
+
+// Deepest reply depth with a bin of its own, deeper tweets are counted together
+pub const MAX_HISTOGRAM_DEPTH: u32 = 5;
+
+// Number of tweets per `t.conversation_depth` set by
+// compute_conversation_depths, from depth 0 up. The tweets deeper than
+// MAX_HISTOGRAM_DEPTH are counted at MAX_HISTOGRAM_DEPTH + 1. Tweets without a
+// depth, in circular reply chains, are left out
+pub async fn compute_reply_depth_histogram(
+    creds: Arc<Credentials>,
+) -> Result<Vec<(u32, u64)>, PipelineError> {
+    println!("Computing the reply depth histogram...");
+    let graph = connect(&creds).await?;
+    let mut result = graph
+        .execute(
+            query(
+                "
+                MATCH (t:Tweet)
+                WHERE t.conversation_depth IS NOT NULL
+                WITH CASE WHEN t.conversation_depth > $max THEN $max + 1
+                     ELSE t.conversation_depth END AS depth
+                RETURN depth, count(*) AS count
+                ORDER BY depth
+                ",
+            )
+            .param("max", MAX_HISTOGRAM_DEPTH as i64),
+        )
+        .await?;
+
+    let mut histogram = Vec::new();
+    while let Some(row) = result.next().await? {
+        histogram.push((
+            row.get::<i64>("depth").unwrap_or(0) as u32,
+            row.get::<i64>("count").unwrap_or(0) as u64,
+        ));
+    }
+    Ok(histogram)
+}
+
+// Longest reply chain followed by compute_conversation_depths
+const MAX_CONVERSATION_DEPTH: i64 = 1000;
+
+// Sets `t.conversation_depth`, the number of REPLIES_TO hops to the root of
+// the conversation, one depth at a time from the roots down. Tweets in a
+// circular reply chain have no root and are left without a depth. Returns the
+// number of tweets with a depth
+pub async fn compute_conversation_depths(creds: Arc<Credentials>) -> Result<u64, PipelineError> {
+    println!("Computing conversation depths...");
+    let graph = connect(&creds).await?;
+
+    // Earlier imports may have added replies above the old roots
+    graph
+        .run(query(
+            "
+            CALL apoc.periodic.iterate(
+              'MATCH (t:Tweet) WHERE t.conversation_depth IS NOT NULL RETURN t',
+              'REMOVE t.conversation_depth',
+              {batchSize: 10000, parallel: true}
+            )
+            ",
+        ))
+        .await?;
+    let mut total = run_depth_step(
+        &graph,
+        "MATCH (t:Tweet) WHERE NOT (t)-[:REPLIES_TO]->(:Tweet) RETURN t",
+        0,
+    )
+    .await?;
+
+    for depth in 1..=MAX_CONVERSATION_DEPTH {
+        let updated = run_depth_step(
+            &graph,
+            "
+            MATCH (t:Tweet)-[:REPLIES_TO]->(parent:Tweet)
+            WHERE parent.conversation_depth = $depth - 1 AND t.conversation_depth IS NULL
+            RETURN t
+            ",
+            depth,
+        )
+        .await?;
+        if updated == 0 {
+            return Ok(total);
+        }
+        total += updated;
+    }
+    eprintln!(
+        "Warning: reply chains deeper than {} were not followed",
+        MAX_CONVERSATION_DEPTH
+    );
+    Ok(total)
+}
+
+async fn run_depth_step(graph: &Graph, tweets: &str, depth: i64) -> Result<u64, PipelineError> {
+    let mut result = graph
+        .execute(
+            query(
+                "
+                CALL apoc.periodic.iterate(
+                  $tweets,
+                  'SET t.conversation_depth = $depth',
+                  {batchSize: 10000, parallel: true, params: {depth: $depth}}
+                ) YIELD total
+                RETURN total AS count
+                ",
+            )
+            .param("tweets", tenant_labels(tweets))
+            .param("depth", depth),
+        )
+        .await?;
+    Ok(match result.next().await? {
+        Some(row) => row.get::<i64>("count").unwrap_or(0) as u64,
+        None => 0,
+    })
+}
+
+// Links every hashtag to the days it was used on, with the number of tweets of
+// that day as `volume` of the TRENDED_ON relation. The Day nodes are created
+// here, the Hashtag nodes and their TAGGED_WITH relations come from
+// migrate_hashtag_array_to_nodes. Needs the temporal properties of the tweets.
+// Returns the number of (hashtag, day) pairs
+pub async fn add_hashtag_trend_properties(creds: Arc<Credentials>) -> Result<u64, PipelineError> {
+    println!("Adding hashtag trends...");
+    let graph = connect(&creds).await?;
+    if count_relations(&graph, "Tweet", "TAGGED_WITH").await? == 0 {
+        return Err(PipelineError::UnexpectedResult(
+            "no TAGGED_WITH relations, run --migrate-hashtags first".to_string(),
+        ));
+    }
+
+    for (name, pattern) in [
+        ("hashtag_name", "(h:Hashtag) ON (h.name)"),
+        ("day_date", "(d:Day) ON (d.year, d.month, d.day)"),
+    ] {
+        graph
+            .run(query(&format!(
+                "CREATE INDEX {} IF NOT EXISTS FOR {}",
+                tenant_name(name),
+                pattern
+            )))
+            .await?;
+    }
+
+    let mut result = graph
+        .execute(
+            query(
+                "
+                CALL apoc.periodic.iterate(
+                  $trends,
+                  '
+                  MERGE (d:Day {year: year, month: month, day: day})
+                  MERGE (h)-[r:TRENDED_ON]->(d)
+                  SET r.volume = volume
+                  ',
+                  {batchSize: 10000, parallel: false}
+                ) YIELD total
+                RETURN total AS count
+                ",
+            )
+            .param(
+                "trends",
+                tenant_labels(
+                    "
+                    MATCH (t:Tweet)-[:TAGGED_WITH]->(h:Hashtag)
+                    WHERE t.year IS NOT NULL
+                    WITH h, t.year AS year, t.month AS month, t.day AS day, count(t) AS volume
+                    RETURN h, year, month, day, volume
+                    ",
+                ),
+            ),
+        )
+        .await?;
+    Ok(match result.next().await? {
+        Some(row) => row.get::<i64>("count").unwrap_or(0) as u64,
+        None => 0,
+    })
+}
+
+pub async fn add_airline_labels(creds: Arc<Credentials>) -> Result<(), PipelineError> {
+    println!("Adding airline labels...");
+    let graph = connect(&creds).await?;
+
+    let mut txn = graph.start_txn().await?;
+    let query = format!(
+        "
+    WITH {:?} AS ids
+    MATCH (n:User)
+    WHERE n.id IN ids
+    SET n:Airline
+        ",
+        AIRLINE_IDS
+    );
+
+    txn.run(self::query(&query)).await.unwrap();
+
+    txn.commit().await?;
+
+    Ok(())
+}
+
+pub async fn record_import_run(
+    creds: Arc<Credentials>,
+    run: &ImportRun,
+) -> Result<(), PipelineError> {
+    let graph = connect(&creds).await?;
+    graph
+        .run(
+            query(
+                "
+                CREATE (r:ImportRun {
+                    id: $id,
+                    started_at: $started_at,
+                    completed_at: $completed_at,
+                    tweets_inserted: $tweets_inserted,
+                    newest_tweet_at: $newest_tweet_at,
+                    sample_rate: $sample_rate,
+                    sample_seed: $sample_seed
+                })
+                ",
+            )
+            .param("id", run.id.to_string())
+            .param("started_at", run.started_at.to_rfc3339())
+            .param("completed_at", run.completed_at.to_rfc3339())
+            .param("tweets_inserted", run.tweets_inserted as i64)
+            .param(
+                "newest_tweet_at",
+                run.newest_tweet_at.map(|date| date.to_rfc3339()),
+            )
+            .param("sample_rate", run.sample_rate)
+            // As a string, Neo4j integers cannot hold every u64
+            .param("sample_seed", run.sample_seed.map(|seed| seed.to_string())),
+        )
+        .await?;
+    Ok(())
+}
+
+// Deletes the tweets imported by a run, with their relationships, and its
+// ImportRun node. Tweets imported by another run as well are kept. Users,
+// places and hashtags are left in place, as are the tweets of runs older than
+// the `t.import_runs` property, which cannot be told apart
+pub async fn purge_import(
+    creds: Arc<Credentials>,
+    run_id: &str,
+) -> Result<PurgeReport, PipelineError> {
+    println!("Purging import run {}...", run_id);
+    let graph = connect(&creds).await?;
+
+    let relationships_deleted = fetch_count_by_id(
+        &graph,
+        "
+        MATCH (t:Tweet)-[r]-()
+        WHERE t.import_runs = [$id]
+        RETURN count(DISTINCT r) AS count
+        ",
+        run_id,
+    )
+    .await?;
+    let tweets_deleted = fetch_count_by_id(
+        &graph,
+        "
+        CALL apoc.periodic.iterate(
+          'MATCH (t:Tweet) WHERE t.import_runs = [$id] RETURN t',
+          'DETACH DELETE t',
+          {batchSize: 10000, parallel: false, params: {id: $id}}
+        ) YIELD total
+        RETURN total AS count
+        ",
+        run_id,
+    )
+    .await?;
+    let tweets_retained = fetch_count_by_id(
+        &graph,
+        "
+        MATCH (t:Tweet)
+        WHERE $id IN t.import_runs
+        SET t.import_runs = [run IN t.import_runs WHERE run <> $id]
+        RETURN count(t) AS count
+        ",
+        run_id,
+    )
+    .await?;
+    graph
+        .run(query("MATCH (r:ImportRun {id: $id}) DELETE r").param("id", run_id))
+        .await?;
+
+    Ok(PurgeReport {
+        tweets_deleted,
+        relationships_deleted,
+        tweets_retained,
+    })
+}
+
+// Same as fetch_count for a query with an `id` parameter
+async fn fetch_count_by_id(graph: &Graph, cypher: &str, id: &str) -> Result<u64, neo4rs::Error> {
+    let mut result = graph.execute(query(cypher).param("id", id)).await?;
+    Ok(match result.next().await? {
+        Some(row) => row.get::<i64>("count").unwrap_or(0) as u64,
+        None => 0,
+    })
+}
+
+// Creation date of the newest tweet imported by a run, `last` stands for the
+// most recently completed run
+pub async fn newest_tweet_of_run(
+    creds: Arc<Credentials>,
+    run_id: &str,
+) -> Result<Option<DateTime<Utc>>, PipelineError> {
+    let graph = connect(&creds).await?;
+    let cypher = if run_id == "last" {
+        "
+        MATCH (r:ImportRun)
+        RETURN r.newest_tweet_at AS newest
+        ORDER BY r.completed_at DESC
+        LIMIT 1
+        "
+    } else {
+        "
+        MATCH (r:ImportRun {id: $id})
+        RETURN r.newest_tweet_at AS newest
+        "
+    };
+    let mut result = graph.execute(query(cypher).param("id", run_id)).await?;
+
+    let Some(row) = result.next().await? else {
+        return Err(PipelineError::UnexpectedResult(format!(
+            "no import run {:?}",
+            run_id
+        )));
+    };
+    let Ok(newest) = row.get::<String>("newest") else {
+        // The run did not import any tweet
+        return Ok(None);
+    };
+    DateTime::parse_from_rfc3339(&newest)
+        .map(|dt| Some(dt.with_timezone(&Utc)))
+        .map_err(|e| {
+            PipelineError::UnexpectedResult(format!("invalid newest_tweet_at {:?}: {}", newest, e))
+        })
+}
+
+pub async fn fetch_graph_stats(creds: Arc<Credentials>) -> Result<GraphStats, PipelineError> {
+    let graph = connect(&creds).await?;
+
+    Ok(GraphStats {
+        tweets: fetch_count(&graph, "MATCH (t:Tweet) RETURN count(t) AS count").await?,
+        users: fetch_count(&graph, "MATCH (u:User) RETURN count(u) AS count").await?,
+        replies_to: count_relations(&graph, "Tweet", "REPLIES_TO").await?,
+        mentions: count_relations(&graph, "Tweet", "MENTIONS").await?,
+    })
+}
+
+// Store size estimate of the graph and the page cache it needs
+#[derive(Debug, Clone, Serialize)]
+pub struct OptimizationReport {
+    pub edition: String,
+    pub nodes: u64,
+    pub relationships: u64,
+    pub estimated_store_bytes: u64,
+    pub recommended_page_cache_bytes: u64,
+    // Name and value of the page cache setting, when the server lists them
+    pub page_cache_setting: String,
+    pub configured_page_cache: Option<String>,
+}
+
+impl fmt::Display for OptimizationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const MB: u64 = 1024 * 1024;
+
+        writeln!(
+            f,
+            "Neo4j {} edition, {} nodes and {} relationships",
+            self.edition, self.nodes, self.relationships
+        )?;
+        writeln!(
+            f,
+            "Estimated store size: {} MB",
+            self.estimated_store_bytes / MB
+        )?;
+        match &self.configured_page_cache {
+            Some(value) => writeln!(f, "Configured {}: {}", self.page_cache_setting, value)?,
+            None => writeln!(
+                f,
+                "Could not read the configured {}",
+                self.page_cache_setting
+            )?,
+        }
+        let recommended = if self.recommended_page_cache_bytes >= 1024 * MB {
+            format!("{}g", self.recommended_page_cache_bytes.div_ceil(1024 * MB))
+        } else {
+            format!("{}m", self.recommended_page_cache_bytes.div_ceil(MB))
+        };
+        write!(
+            f,
+            "Recommendation: `{}={}` in neo4j.conf, so that the whole store fits in memory",
+            self.page_cache_setting, recommended
+        )
+    }
+}
+
+// Estimates the store size from the node and relationship counts and a sample
+// of the node properties, and compares the page cache it needs with the
+// configured one. Works on Community and Enterprise, the settings are only
+// reported when the server lets us list them
+pub async fn optimize_graph(creds: Arc<Credentials>) -> Result<OptimizationReport, PipelineError> {
+    // Record sizes of the Neo4j store format
+    const NODE_RECORD_BYTES: u64 = 15;
+    const RELATIONSHIP_RECORD_BYTES: u64 = 34;
+    // Room for the indexes and for growth
+    const PAGE_CACHE_MARGIN: f64 = 1.2;
+    const MIN_PAGE_CACHE_BYTES: u64 = 512 * 1024 * 1024;
+
+    let version = neo4j_version(creds.clone()).await;
+    let graph = connect(&creds).await?;
+
+    let mut result = graph
+        .execute(query("CALL dbms.components() YIELD edition RETURN edition"))
+        .await?;
+    let edition = match result.next().await? {
+        Some(row) => row.get::<String>("edition").unwrap_or_default(),
+        None => String::new(),
+    };
+
+    let nodes = fetch_count(&graph, "MATCH (n) RETURN count(n) AS count").await?;
+    let relationships = fetch_count(&graph, "MATCH ()-[r]->() RETURN count(r) AS count").await?;
+    let property_bytes = fetch_count(
+        &graph,
+        "
+        MATCH (n) WITH n LIMIT 1000
+        RETURN toInteger(avg(size(apoc.convert.toJson(properties(n))))) AS count
+        ",
+    )
+    .await?;
+
+    let estimated_store_bytes =
+        nodes * (NODE_RECORD_BYTES + property_bytes) + relationships * RELATIONSHIP_RECORD_BYTES;
+    let recommended_page_cache_bytes =
+        ((estimated_store_bytes as f64 * PAGE_CACHE_MARGIN) as u64).max(MIN_PAGE_CACHE_BYTES);
+
+    let (page_cache_setting, settings_query) = if version.major >= 5 {
+        (
+            "server.memory.pagecache.size",
+            "SHOW SETTINGS YIELD name, value WHERE name = $name RETURN value",
+        )
+    } else {
+        (
+            "dbms.memory.pagecache.size",
+            "CALL dbms.listConfig($name) YIELD value RETURN value",
+        )
+    };
+    let configured_page_cache = match graph
+        .execute(query(settings_query).param("name", page_cache_setting))
+        .await
+    {
+        Ok(mut result) => match result.next().await {
+            Ok(Some(row)) => row.get::<String>("value").ok(),
+            _ => None,
+        },
+        Err(_) => None,
+    };
+
+    Ok(OptimizationReport {
+        edition,
+        nodes,
+        relationships,
+        estimated_store_bytes,
+        recommended_page_cache_bytes,
+        page_cache_setting: page_cache_setting.to_owned(),
+        configured_page_cache,
+    })
+}
+
+// Lists what the database currently holds, requires Neo4j 4.4 or later for
+// SHOW CONSTRAINTS
+pub async fn fetch_schema(creds: Arc<Credentials>) -> Result<SchemaReport, PipelineError> {
+    let graph = connect(&creds).await?;
+
+    Ok(SchemaReport {
+        constraints: fetch_schema_items(
+            &graph,
+            "SHOW CONSTRAINTS YIELD name, type, labelsOrTypes, properties
+            RETURN name, type, labelsOrTypes, properties, null AS state",
+        )
+        .await?,
+        indexes: fetch_schema_items(
+            &graph,
+            "SHOW INDEXES YIELD name, type, labelsOrTypes, properties, state
+            RETURN name, type, labelsOrTypes, properties, state",
+        )
+        .await?,
+        labels: fetch_names(&graph, "CALL db.labels() YIELD label RETURN label AS name").await?,
+        relationship_types: fetch_names(
+            &graph,
+            "CALL db.relationshipTypes() YIELD relationshipType RETURN relationshipType AS name",
+        )
+        .await?,
+    })
+}
+
+// Node count and number of nodes missing each property, per label, or for one
+// label only. Scans every node of the labels once per property, so it is slow
+// on large graphs. The keys are the labels as stored, with the tenant prefix
+pub async fn label_statistics(
+    creds: Arc<Credentials>,
+    only_label: Option<&str>,
+) -> Result<HashMap<String, LabelStats>, PipelineError> {
+    let graph = connect(&creds).await?;
+    let only_label = only_label.map(stored_label);
+    let mut result = graph
+        .execute(neo4rs::query(
+            "
+            CALL db.schema.nodeTypeProperties() YIELD nodeLabels, propertyName
+            WHERE propertyName IS NOT NULL
+            UNWIND nodeLabels AS label
+            RETURN label, collect(DISTINCT propertyName) AS keys
+            ",
+        ))
+        .await?;
+    let mut keys_by_label = Vec::new();
+    while let Some(row) = result.next().await? {
+        let label = row.get::<String>("label").unwrap_or_default();
+        if only_label.as_ref().is_none_or(|only| *only == label) {
+            let mut keys = row.get::<Vec<String>>("keys").unwrap_or_default();
+            keys.sort();
+            keys_by_label.push((label, keys));
+        }
+    }
+
+    let mut stats = HashMap::new();
+    for (label, property_keys) in keys_by_label {
+        // The labels are already stored names, so they bypass tenant_labels
+        let mut label_stats = LabelStats {
+            node_count: fetch_stored_count(
+                &graph,
+                &format!(
+                    "MATCH (n:{}) RETURN count(n) AS count",
+                    quote_identifier(&label)
+                ),
+            )
+            .await?,
+            ..Default::default()
+        };
+        for key in &property_keys {
+            let nulls = fetch_stored_count(
+                &graph,
+                &format!(
+                    "MATCH (n:{}) WHERE n.{} IS NULL RETURN count(n) AS count",
+                    quote_identifier(&label),
+                    quote_identifier(key)
+                ),
+            )
+            .await?;
+            label_stats
+                .null_count_by_property
+                .insert(key.clone(), nulls);
+        }
+        label_stats.property_keys = property_keys;
+        stats.insert(label, label_stats);
+    }
+    Ok(stats)
+}
+
+// Quotes a label or property key, which can hold any character once it is
+// read back from the database, backticks included
+fn quote_identifier(name: &str) -> String {
+    format!("`{}`", name.replace('`', "``"))
+}
+
+// Name of a label in the database, with the tenant prefix for the labels that get one
+fn stored_label(label: &str) -> String {
+    match TENANT_PREFIX.get() {
+        Some(prefix) if TENANT_LABELS.contains(&label) => format!("{}_{}", prefix, label),
+        _ => label.to_owned(),
+    }
+}
+
+async fn fetch_stored_count(graph: &Graph, cypher: &str) -> Result<u64, neo4rs::Error> {
+    let mut result = graph.execute(neo4rs::query(cypher)).await?;
+    Ok(match result.next().await? {
+        Some(row) => row.get::<i64>("count").unwrap_or(0) as u64,
+        None => 0,
+    })
+}
+
+async fn fetch_schema_items(graph: &Graph, cypher: &str) -> Result<Vec<SchemaItem>, neo4rs::Error> {
+    let mut result = graph.execute(query(cypher)).await?;
+
+    let mut items = Vec::new();
+    while let Some(row) = result.next().await? {
+        items.push(SchemaItem {
+            name: row.get::<String>("name").unwrap_or_default(),
+            kind: row.get::<String>("type").unwrap_or_default(),
+            // Null for the token lookup indexes
+            labels: row.get::<Vec<String>>("labelsOrTypes").unwrap_or_default(),
+            properties: row.get::<Vec<String>>("properties").unwrap_or_default(),
+            state: row.get::<String>("state").ok(),
+        });
+    }
+    Ok(items)
+}
+
+// Expects a query returning a `name` column
+async fn fetch_names(graph: &Graph, cypher: &str) -> Result<Vec<String>, neo4rs::Error> {
+    let mut result = graph.execute(query(cypher)).await?;
+
+    let mut names = Vec::new();
+    while let Some(row) = result.next().await? {
+        names.push(row.get::<String>("name").unwrap_or_default());
+    }
+    Ok(names)
+}
+
+pub async fn run_consistency_checks(
+    creds: Arc<Credentials>,
+) -> Result<ConsistencyReport, PipelineError> {
+    println!("Checking graph consistency...");
+    let graph = connect(&creds).await?;
+
+    let dangling_replies = fetch_count(
+        &graph,
+        "MATCH (:Tweet)-[r:REPLIES_TO]->(n) WHERE NOT n:Tweet RETURN count(r) AS count",
+    )
+    .await?;
+    let tweets_without_author = fetch_count(
+        &graph,
+        "MATCH (t:Tweet) WHERE NOT (t)-[:POSTED_BY]->(:User) RETURN count(t) AS count",
+    )
+    .await?;
+    let users_without_tweets = fetch_count(
+        &graph,
+        "MATCH (u:User) WHERE NOT (u)<-[:POSTED_BY]-(:Tweet) RETURN count(u) AS count",
+    )
+    .await?;
+
+    Ok(ConsistencyReport {
+        dangling_replies,
+        tweets_without_author,
+        users_without_tweets,
+    })
+}
+
+pub async fn query_tweets_by_hashtag(
+    creds: Arc<Credentials>,
+    hashtag: &str,
+    limit: usize,
+) -> Result<Vec<TweetSummary>, PipelineError> {
+    let graph = connect(&creds).await?;
+    let mut result = graph
+        .execute(
+            query(
+                "
+                MATCH (t:Tweet)
+                WHERE $tag IN t.hashtags
+                RETURN t.id AS id, t.text AS text, t.created_at AS created_at
+                LIMIT $limit
+                ",
+            )
+            .param("tag", hashtag)
+            .param("limit", limit as i64),
+        )
+        .await?;
+
+    let mut tweets = Vec::new();
+    while let Some(row) = result.next().await? {
+        let created_at = row.get::<String>("created_at").unwrap_or_default();
+        tweets.push(TweetSummary {
+            id: row.get::<String>("id").unwrap_or_default(),
+            text: row.get::<String>("text").unwrap_or_default(),
+            created_at: DateTime::parse_from_rfc3339(&created_at)
+                .map(|dt| dt.with_timezone(&Utc))
+                .map_err(|e| {
+                    PipelineError::UnexpectedResult(format!(
+                        "invalid created_at {:?}: {}",
+                        created_at, e
+                    ))
+                })?,
+        });
+    }
+    Ok(tweets)
+}
+
+// Number of tweets per language, most common first. Tweets without a language
+// count as `und`, like those Twitter could not classify
+pub async fn count_tweets_by_language(
+    creds: Arc<Credentials>,
+) -> Result<Vec<(String, u64)>, PipelineError> {
+    let graph = connect(&creds).await?;
+    let mut result = graph
+        .execute(query(
+            "
+            MATCH (t:Tweet)
+            RETURN coalesce(t.lang, 'und') AS lang, count(t) AS n
+            ORDER BY n DESC
+            ",
+        ))
+        .await?;
+
+    let mut languages = Vec::new();
+    while let Some(row) = result.next().await? {
+        languages.push((
+            row.get::<String>("lang").unwrap_or_default(),
+            row.get::<i64>("n").unwrap_or_default() as u64,
+        ));
+    }
+    Ok(languages)
+}
+
+// Example query of the sample queries file, with what the graph must hold for
+// it to return anything
+struct SampleQuery {
+    labels: &'static [&'static str],
+    relationship_types: &'static [&'static str],
+    property_keys: &'static [&'static str],
+    cypher: &'static str,
+}
+
+const SAMPLE_QUERIES: [SampleQuery; 10] = [
+    SampleQuery {
+        labels: &["Tweet"],
+        relationship_types: &[],
+        property_keys: &["hashtags"],
+        cypher: "\
+// Top 20 hashtags
+MATCH (t:Tweet)
+UNWIND t.hashtags AS hashtag
+RETURN hashtag, count(*) AS tweets
+ORDER BY tweets DESC
+LIMIT 20;
+",
+    },
+    SampleQuery {
+        labels: &["Tweet", "Hashtag"],
+        relationship_types: &["TAGGED_WITH"],
+        property_keys: &[],
+        cypher: "\
+// Top 20 hashtag nodes, from --migrate-hashtags
+MATCH (:Tweet)-[:TAGGED_WITH]->(h:Hashtag)
+RETURN h.name AS hashtag, count(*) AS tweets
+ORDER BY tweets DESC
+LIMIT 20;
+",
+    },
+    SampleQuery {
+        labels: &["Tweet"],
+        relationship_types: &["REPLIES_TO"],
+        property_keys: &[],
+        cypher: "\
+// Longest reply chains
+MATCH path = (:Tweet)-[:REPLIES_TO*]->(root:Tweet)
+WHERE NOT (root)-[:REPLIES_TO]->()
+RETURN root.id AS root, length(path) AS depth
+ORDER BY depth DESC
+LIMIT 10;
+",
+    },
+    SampleQuery {
+        labels: &["User"],
+        relationship_types: &[],
+        property_keys: &["followers_count"],
+        cypher: "\
+// Most followed users
+MATCH (u:User)
+RETURN u.name AS user, u.followers_count AS followers
+ORDER BY followers DESC
+LIMIT 20;
+",
+    },
+    SampleQuery {
+        labels: &["Tweet"],
+        relationship_types: &[],
+        property_keys: &["lang"],
+        cypher: "\
+// Tweets by language
+MATCH (t:Tweet)
+RETURN t.lang AS lang, count(*) AS tweets
+ORDER BY tweets DESC;
+",
+    },
+    SampleQuery {
+        labels: &["Tweet", "User"],
+        relationship_types: &["MENTIONS"],
+        property_keys: &[],
+        cypher: "\
+// Most mentioned users
+MATCH (:Tweet)-[:MENTIONS]->(u:User)
+RETURN u.name AS user, count(*) AS mentions
+ORDER BY mentions DESC
+LIMIT 20;
+",
+    },
+    SampleQuery {
+        labels: &["Tweet", "Airline"],
+        relationship_types: &["POSTED_BY", "REPLIES_TO"],
+        property_keys: &[],
+        cypher: "\
+// Airlines and the number of replies they sent
+MATCH (a:Airline)<-[:POSTED_BY]-(t:Tweet)-[:REPLIES_TO]->(:Tweet)
+RETURN a.name AS airline, count(t) AS replies
+ORDER BY replies DESC;
+",
+    },
+    SampleQuery {
+        labels: &["Tweet"],
+        relationship_types: &[],
+        property_keys: &["location"],
+        cypher: "\
+// Geo-tagged tweets within 50 km of Amsterdam
+MATCH (t:Tweet)
+WHERE point.distance(t.location, point({longitude: 4.9, latitude: 52.37})) < 50000
+RETURN t.id, t.text;
+",
+    },
+    SampleQuery {
+        labels: &["Tweet"],
+        relationship_types: &[],
+        property_keys: &["edit_history", "is_original"],
+        cypher: "\
+// Tweets that were edited, with their number of versions
+MATCH (t:Tweet)
+WHERE size(t.edit_history) > 1 AND t.is_original
+RETURN t.id, size(t.edit_history) AS versions
+ORDER BY versions DESC;
+",
+    },
+    SampleQuery {
+        labels: &["Tweet"],
+        relationship_types: &[],
+        property_keys: &["raw_json"],
+        cypher: "\
+// Fields that are not imported can be read from the original JSON with APOC
+MATCH (t:Tweet)
+WHERE t.raw_json IS NOT NULL
+RETURN t.id, apoc.convert.fromJsonMap(t.raw_json).source AS source
+LIMIT 20;
+",
+    },
+];
+
+// Labels, relationship types and property keys present in the database
+#[derive(Debug, Default)]
+struct GraphSchema {
+    labels: HashSet<String>,
+    relationship_types: HashSet<String>,
+    property_keys: HashSet<String>,
+}
+
+impl GraphSchema {
+    async fn fetch(graph: &Graph) -> Result<Self, neo4rs::Error> {
+        Ok(Self {
+            labels: fetch_names(graph, "CALL db.labels() YIELD label RETURN label AS name")
+                .await?
+                .into_iter()
+                .collect(),
+            relationship_types: fetch_names(
+                graph,
+                "CALL db.relationshipTypes() YIELD relationshipType RETURN relationshipType AS name",
+            )
+            .await?
+            .into_iter()
+            .collect(),
+            property_keys: fetch_names(
+                graph,
+                "CALL db.propertyKeys() YIELD propertyKey RETURN propertyKey AS name",
+            )
+            .await?
+            .into_iter()
+            .collect(),
+        })
+    }
+
+    // Labels are compared with their tenant prefix, the property keys are
+    // shared by all tenants
+    fn supports(&self, sample: &SampleQuery) -> bool {
+        sample
+            .labels
+            .iter()
+            .all(|label| self.labels.contains(&stored_label(label)))
+            && sample
+                .relationship_types
+                .iter()
+                .all(|rel_type| self.relationship_types.contains(*rel_type))
+            && sample
+                .property_keys
+                .iter()
+                .all(|key| self.property_keys.contains(*key))
+    }
+}
+
+// The sample queries that can return something on a graph with this schema
+fn sample_queries(schema: &GraphSchema) -> String {
+    let mut queries =
+        String::from("// Example queries for the imported graph, generated by data-pipeline\n");
+    for sample in SAMPLE_QUERIES
+        .iter()
+        .filter(|sample| schema.supports(sample))
+    {
+        queries.push('\n');
+        queries.push_str(sample.cypher);
+    }
+    tenant_labels(&queries)
+}
+
+// Writes annotated example queries for the features present in the graph,
+// using the labels of the current tenant
+pub async fn create_sample_queries_file(
+    creds: Arc<Credentials>,
+    path: &Path,
+) -> Result<(), PipelineError> {
+    let graph = connect(&creds).await?;
+    let schema = GraphSchema::fetch(&graph).await?;
+    fs::write(path, sample_queries(&schema))?;
+    Ok(())
+}
+
+// Streams the given properties of every node with the label into a CSV file,
+// returns the number of rows written
+pub async fn export_nodes_csv(
+    creds: Arc<Credentials>,
+    label: &str,
+    properties: &[&str],
+    path: &Path,
+) -> Result<u64, PipelineError> {
+    // Labels and property keys cannot be query parameters, so they are
+    // restricted to plain identifiers before being put in the query
+    for name in std::iter::once(&label).chain(properties) {
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(PipelineError::UnexpectedResult(format!(
+                "invalid label or property name {:?}",
+                name
+            )));
+        }
+    }
+    let columns: Vec<String> = properties
+        .iter()
+        .map(|prop| format!("n.{prop} AS {prop}"))
+        .collect();
+
+    let mut writer = csv::Writer::from_path(path)?;
+    writer.write_record(properties)?;
+
+    let graph = connect(&creds).await?;
+    let mut result = graph
+        .execute(query(&format!(
+            "MATCH (n:{}) RETURN {}",
+            label,
+            columns.join(", ")
+        )))
+        .await?;
+
+    let mut rows = 0;
+    while let Some(row) = result.next().await? {
+        let record: Vec<String> = properties
+            .iter()
+            .map(|prop| {
+                row.get::<neo4rs::BoltType>(prop)
+                    .map(|value| csv_field(&value))
+                    .unwrap_or_default()
+            })
+            .collect();
+        writer.write_record(&record)?;
+        rows += 1;
+    }
+    writer.flush().map_err(csv::Error::from)?;
+    Ok(rows)
+}
+
+// Lists are joined with `;` so that they fit in a single CSV field
+fn csv_field(value: &neo4rs::BoltType) -> String {
+    match value {
+        neo4rs::BoltType::Null(_) => String::new(),
+        neo4rs::BoltType::String(s) => s.value.clone(),
+        neo4rs::BoltType::Integer(i) => i.value.to_string(),
+        neo4rs::BoltType::Float(f) => f.value.to_string(),
+        neo4rs::BoltType::Boolean(b) => b.value.to_string(),
+        neo4rs::BoltType::List(list) => list
+            .value
+            .iter()
+            .map(csv_field)
+            .collect::<Vec<_>>()
+            .join(";"),
+        other => format!("{:?}", other),
+    }
+}
+
+// Normalizes the hashtags and the screen name, which are matched on in queries
+pub fn normalize_tweet(tweet: &mut json::Tweet, normalization: UnicodeNormForm) {
+    tweet.entities.hashtags = tweet
+        .entities
+        .hashtags
+        .iter()
+        .map(|tag| normalization.apply(tag))
+        .collect();
+    tweet.entities.user_mentions = tweet
+        .entities
+        .user_mentions
+        .iter()
+        .map(|name| normalization.apply(name))
+        .collect();
+    tweet.user.screen_name = normalization.apply(&tweet.user.screen_name);
+}
+
+fn prepare_batch_parameters(
+    mut chunk_vec: Vec<json::Tweet>,
+    options: &InsertOptions,
+) -> BatchParameters {
+    for tweet in &mut chunk_vec {
+        normalize_tweet(tweet, options.text_normalization);
+    }
+
+    // Only the selected fields are sent, next to the keys the queries always use
+    let mut tweet_keys: HashSet<String> = ["id", "userId", "qualityScore", "rawJson"]
+        .into_iter()
+        .map(str::to_owned)
+        .collect();
+    #[cfg(feature = "extra_fields")]
+    if options.preserve_extra_fields {
+        tweet_keys.insert("extraJson".to_owned());
+    }
+    tweet_keys.extend(
+        options
+            .fields
+            .tweet_fields()
+            .flat_map(TweetField::param_keys),
+    );
+    let mut user_keys: HashSet<String> = HashSet::from(["id".to_owned()]);
+    user_keys.extend(options.fields.user_fields().map(UserField::property));
+
+    let tweets = chunk_vec
+        .iter()
+        .map(|tweet| {
+            let mut tweet_map = HashMap::from(tweet);
+            tweet_map.retain(|key, _| tweet_keys.contains(key));
             tweet_map
         })
         .collect();
-    return batch;
+
+    // Users are deduplicated so every user node is merged once per batch,
+    // the first tweet of a user in the batch provides its properties
+    let mut seen_users = HashSet::new();
+    let users = chunk_vec
+        .iter()
+        .filter(|tweet| seen_users.insert(tweet.user.id_str.clone()))
+        .map(|tweet| {
+            let mut user_map = HashMap::from(&tweet.user);
+            user_map.retain(|key, _| user_keys.contains(key));
+            user_map
+        })
+        .collect();
+
+    BatchParameters { tweets, users }
+}
+
+// Missing optional fields are sent as an explicit null, so the property is
+// not set on the node rather than stored as a "null" string
+fn nullable<T: Into<neo4rs::BoltType>>(value: Option<T>) -> neo4rs::BoltType {
+    value.map_or(neo4rs::BoltType::Null(neo4rs::BoltNull), Into::into)
+}
+
+// Query parameters of a tweet, as used by `QueryBuilder::build_insert_query`
+impl From<&json::Tweet> for HashMap<String, neo4rs::BoltType> {
+    fn from(tweet: &json::Tweet) -> Self {
+        let mut tweet_map = HashMap::new();
+
+        tweet_map.insert("id".to_string(), tweet.id_str.clone().into());
+        tweet_map.insert("text".to_string(), tweet.text.clone().into());
+        tweet_map.insert(
+            "created_at".to_string(),
+            tweet.created_at.to_rfc3339().into(),
+        );
+        tweet_map.insert("reply_to".to_string(), nullable(tweet.reply_to.clone()));
+        tweet_map.insert(
+            "reply_to_user_id".to_string(),
+            nullable(tweet.reply_to_user_id.clone()),
+        );
+        tweet_map.insert("lang".to_string(), nullable(tweet.lang.clone()));
+        tweet_map.insert(
+            "hashtags".to_string(),
+            tweet.entities.hashtags.clone().into(),
+        );
+        tweet_map.insert(
+            "user_mentions".to_string(),
+            tweet.entities.user_mentions.clone().into(),
+        );
+        tweet_map.insert(
+            "edit_history".to_string(),
+            nullable(tweet.edit_history_tweet_ids.clone()),
+        );
+        tweet_map.insert(
+            "cashtags".to_string(),
+            tweet.entities.symbols.clone().into(),
+        );
+        tweet_map.insert(
+            "urls".to_string(),
+            tweet
+                .entities
+                .urls
+                .iter()
+                .map(|url| url.expanded_url.clone().unwrap_or_else(|| url.url.clone()))
+                .collect::<Vec<_>>()
+                .into(),
+        );
+        tweet_map.insert(
+            "longitude".to_string(),
+            nullable(tweet.coordinates.map(|c| c[0])),
+        );
+        tweet_map.insert(
+            "latitude".to_string(),
+            nullable(tweet.coordinates.map(|c| c[1])),
+        );
+        tweet_map.insert("retweet_count".to_string(), nullable(tweet.retweet_count));
+        tweet_map.insert("favorite_count".to_string(), nullable(tweet.favorite_count));
+        tweet_map.insert(
+            "scopePlaceIds".to_string(),
+            nullable(tweet.scopes.as_ref().map(|s| s.place_ids.clone())),
+        );
+        tweet_map.insert(
+            "qualityScore".to_string(),
+            (crate::quality::tweet_quality_score(tweet) as f64).into(),
+        );
+        tweet_map.insert("rawJson".to_string(), nullable(tweet.raw_json.clone()));
+        #[cfg(feature = "extra_fields")]
+        if !tweet.extra.is_empty() {
+            tweet_map.insert(
+                "extraJson".to_string(),
+                // String keys and JSON values always serialize
+                serde_json::to_string(&tweet.extra)
+                    .expect("a map of JSON values serializes")
+                    .into(),
+            );
+        }
+        tweet_map.insert("userId".to_string(), tweet.user.id_str.clone().into());
+        tweet_map
+    }
+}
+
+// Query parameters of a user, as used by `QueryBuilder::build_users_query` and
+// `upsert_users_bulk`
+impl From<&json::User> for HashMap<String, neo4rs::BoltType> {
+    fn from(user: &json::User) -> Self {
+        let mut user_map = HashMap::new();
+
+        user_map.insert("id".to_string(), user.id_str.clone().into());
+        user_map.insert("name".to_string(), user.screen_name.clone().into());
+        user_map.insert("location".to_string(), nullable(user.location.clone()));
+        user_map.insert("verified".to_string(), user.verified.into());
+        user_map.insert("followers_count".to_string(), user.followers_count.into());
+        user_map.insert("friends_count".to_string(), user.friends_count.into());
+        user_map.insert("listed_count".to_string(), nullable(user.listed_count));
+        user_map.insert("favourites_count".to_string(), user.favourites_count.into());
+        user_map.insert("statuses_count".to_string(), user.statuses_count.into());
+        user_map.insert(
+            "created_at".to_string(),
+            user.created_at.to_rfc3339().into(),
+        );
+        user_map.insert("utc_offset".to_string(), nullable(user.utc_offset));
+        user_map.insert("protected".to_string(), nullable(user.protected));
+        user_map
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use neo4rs::BoltType;
+
+    fn tweet() -> json::Tweet {
+        serde_json::from_str(
+            r#"{
+                "created_at": "Thu May 23 14:54:46 +0000 2019",
+                "id_str": "1131580395845767168",
+                "in_reply_to_status_id_str": null,
+                "user": {
+                    "id_str": "1001",
+                    "screen_name": "traveller",
+                    "location": null,
+                    "followers_count": 120,
+                    "friends_count": 80,
+                    "listed_count": null,
+                    "favourites_count": 15,
+                    "statuses_count": 2300,
+                    "created_at": "Mon Jan 05 10:00:00 +0000 2015",
+                    "utc_offset": null,
+                    "protected": null
+                }
+            }"#,
+        )
+        .unwrap()
+    }
+
+    // Precomposed "é" against "e" followed by a combining acute accent
+    const PRECOMPOSED: &str = "caf\u{e9}";
+    const DECOMPOSED: &str = "cafe\u{301}";
+
+    #[test]
+    fn nfc_joins_precomposed_and_decomposed_text() {
+        let nfc = UnicodeNormForm::Nfc;
+        assert_eq!(nfc.apply(DECOMPOSED), PRECOMPOSED);
+        assert_eq!(nfc.apply(PRECOMPOSED), PRECOMPOSED);
+        // Compatibility characters are left alone
+        assert_eq!(nfc.apply("\u{fb01}ght"), "\u{fb01}ght");
+    }
+
+    #[test]
+    fn nfkc_also_folds_compatibility_characters() {
+        let nfkc = UnicodeNormForm::Nfkc;
+        assert_eq!(nfkc.apply(DECOMPOSED), PRECOMPOSED);
+        assert_eq!(nfkc.apply("\u{fb01}ght"), "fight");
+        assert_eq!(nfkc.apply("\u{ff2b}\u{ff2c}\u{ff2d}"), "KLM");
+    }
+
+    #[test]
+    fn none_keeps_the_text_as_is() {
+        assert_eq!(UnicodeNormForm::None.apply(DECOMPOSED), DECOMPOSED);
+    }
+
+    #[test]
+    fn normalize_tweet_covers_hashtags_and_screen_names() {
+        let mut tweet = tweet();
+        tweet.entities.hashtags = vec![DECOMPOSED.to_string()];
+        tweet.entities.user_mentions = vec![DECOMPOSED.to_string()];
+        tweet.user.screen_name = DECOMPOSED.to_string();
+
+        normalize_tweet(&mut tweet, UnicodeNormForm::Nfc);
+        assert_eq!(tweet.entities.hashtags, [PRECOMPOSED]);
+        assert_eq!(tweet.entities.user_mentions, [PRECOMPOSED]);
+        assert_eq!(tweet.user.screen_name, PRECOMPOSED);
+    }
+
+    #[test]
+    fn tweet_converts_to_query_parameters() {
+        let mut tweet = tweet();
+        tweet.text = "Delayed again at AMS #KLM".to_string();
+        tweet.reply_to = Some("1131580000000000000".to_string());
+        tweet.lang = Some("en".to_string());
+        tweet.entities.hashtags = vec!["KLM".to_string()];
+        tweet.entities.urls = vec![json::UrlEntity {
+            url: "https://t.co/abc".to_string(),
+            expanded_url: None,
+        }];
+        tweet.coordinates = Some([4.9, 52.37]);
+        tweet.retweet_count = Some(3);
+
+        let map = HashMap::<String, BoltType>::from(&tweet);
+        let get = |key: &str| map.get(key).unwrap_or_else(|| panic!("no {}", key)).clone();
+        assert_eq!(get("id"), BoltType::from("1131580395845767168"));
+        assert_eq!(get("text"), BoltType::from("Delayed again at AMS #KLM"));
+        assert_eq!(
+            get("created_at"),
+            BoltType::from("2019-05-23T14:54:46+00:00")
+        );
+        assert_eq!(get("reply_to"), BoltType::from("1131580000000000000"));
+        assert_eq!(get("lang"), BoltType::from("en"));
+        assert_eq!(get("hashtags"), BoltType::from(vec!["KLM".to_string()]));
+        assert_eq!(
+            get("urls"),
+            BoltType::from(vec!["https://t.co/abc".to_string()])
+        );
+        assert_eq!(get("longitude"), BoltType::from(4.9));
+        assert_eq!(get("latitude"), BoltType::from(52.37));
+        assert_eq!(get("retweet_count"), BoltType::from(3_i64));
+        assert_eq!(get("userId"), BoltType::from("1001"));
+    }
+
+    fn assert_null(map: &HashMap<String, BoltType>, keys: &[&str]) {
+        for key in keys {
+            assert!(
+                matches!(map.get(*key), Some(BoltType::Null(_))),
+                "{} is {:?}",
+                key,
+                map.get(*key)
+            );
+        }
+    }
+
+    #[test]
+    fn absent_tweet_fields_are_null() {
+        let map = HashMap::<String, BoltType>::from(&tweet());
+        assert_null(
+            &map,
+            &[
+                "reply_to",
+                "reply_to_user_id",
+                "lang",
+                "edit_history",
+                "longitude",
+                "latitude",
+                "retweet_count",
+                "favorite_count",
+                "scopePlaceIds",
+                "rawJson",
+            ],
+        );
+    }
+
+    #[test]
+    fn absent_user_fields_are_null() {
+        let map = HashMap::<String, BoltType>::from(&tweet().user);
+        assert_null(
+            &map,
+            &["location", "listed_count", "utc_offset", "protected"],
+        );
+    }
+
+    // Splits a Cypher literal into its string literals, read back as Cypher
+    // would, and the rest of it with each string literal replaced by ''
+    fn split_cypher_strings(literal: &str) -> (String, Vec<String>) {
+        let mut skeleton = String::new();
+        let mut strings = Vec::new();
+        let mut chars = literal.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\'' => {
+                    strings.push(read_string_body(&mut chars, literal));
+                    skeleton.push_str("''");
+                }
+                // Backticks are escaped by doubling them, which reads as two
+                // quoted names in a row here
+                '`' => {
+                    skeleton.push('`');
+                    for c in chars.by_ref() {
+                        skeleton.push(c);
+                        if c == '`' {
+                            break;
+                        }
+                    }
+                }
+                c => skeleton.push(c),
+            }
+        }
+        (skeleton, strings)
+    }
+
+    // Reads a string literal up to its closing quote
+    fn read_string_body(chars: &mut std::str::Chars, literal: &str) -> String {
+        let mut value = String::new();
+        loop {
+            let c = chars
+                .next()
+                .unwrap_or_else(|| panic!("unterminated string in {}", literal));
+            assert!(!c.is_control(), "raw control character in {:?}", literal);
+            match c {
+                '\'' => return value,
+                '\\' => match chars.next() {
+                    Some('\\') => value.push('\\'),
+                    Some('\'') => value.push('\''),
+                    Some('n') => value.push('\n'),
+                    Some('r') => value.push('\r'),
+                    Some('t') => value.push('\t'),
+                    Some('u') => {
+                        let hex: String = chars.by_ref().take(4).collect();
+                        let code = u32::from_str_radix(&hex, 16).unwrap();
+                        value.push(char::from_u32(code).unwrap());
+                    }
+                    other => panic!("unknown escape {:?} in {}", other, literal),
+                },
+                c => value.push(c),
+            }
+        }
+    }
+
+    // Reads a string literal of cypher_literal back, as Cypher would
+    fn read_cypher_string(literal: &str) -> String {
+        let (skeleton, mut strings) = split_cypher_strings(literal);
+        assert_eq!(skeleton, "''", "not a single string literal: {}", literal);
+        strings.remove(0)
+    }
+
+    #[test]
+    fn strings_round_trip_through_cypher_literal() {
+        for value in [
+            "",
+            "it's",
+            "say \"hi\"",
+            "C:\\path\\",
+            "trailing backslash \\",
+            "\\'",
+            "line\nbreak",
+            "crlf\r\n",
+            "tab\tand nul\u{0} and bell\u{7}",
+            "caf\u{e9} cafe\u{301} \u{1f6eb} \u{5e9}\u{5dc}\u{5d5}\u{5dd} \u{2028}",
+        ] {
+            let literal = cypher_literal(&BoltType::from(value));
+            assert_eq!(read_cypher_string(&literal), value);
+        }
+    }
+
+    #[test]
+    fn finite_floats_round_trip_through_cypher_literal() {
+        for value in [
+            0.0,
+            -0.0,
+            0.1,
+            1.0,
+            -2.5,
+            1e300,
+            f64::MIN_POSITIVE,
+            f64::MAX,
+        ] {
+            let literal = cypher_literal(&BoltType::from(value));
+            assert!(
+                literal.contains('.') || literal.contains('e'),
+                "{}",
+                literal
+            );
+            assert_eq!(literal.parse::<f64>().unwrap().to_bits(), value.to_bits());
+        }
+    }
+
+    #[test]
+    fn non_finite_floats_are_written_as_divisions() {
+        assert_eq!(cypher_literal(&BoltType::from(f64::NAN)), "(0.0 / 0.0)");
+        assert_eq!(
+            cypher_literal(&BoltType::from(f64::INFINITY)),
+            "(1.0 / 0.0)"
+        );
+        assert_eq!(
+            cypher_literal(&BoltType::from(f64::NEG_INFINITY)),
+            "(-1.0 / 0.0)"
+        );
+        let list = BoltType::from(vec![1.5, f64::NAN]);
+        assert_eq!(cypher_literal(&list), "[1.5, (0.0 / 0.0)]");
+    }
+
+    #[test]
+    fn other_types_are_written_as_escaped_strings() {
+        let bytes = BoltType::Bytes(neo4rs::BoltBytes::new(vec![b'\'', b'\n'].into()));
+        let literal = cypher_literal(&bytes);
+        assert_eq!(read_cypher_string(&literal), format!("{:?}", bytes));
+    }
+
+    #[test]
+    fn stored_names_are_quoted_with_doubled_backticks() {
+        assert_eq!(quote_identifier("Tweet"), "`Tweet`");
+        assert_eq!(quote_identifier("has space"), "`has space`");
+        assert_eq!(
+            quote_identifier("x`) DETACH DELETE n //"),
+            "`x``) DETACH DELETE n //`"
+        );
+    }
+
+    fn schema(labels: &[&str], relationship_types: &[&str], property_keys: &[&str]) -> GraphSchema {
+        let names = |names: &[&str]| names.iter().map(|name| name.to_string()).collect();
+        GraphSchema {
+            labels: names(labels),
+            relationship_types: names(relationship_types),
+            property_keys: names(property_keys),
+        }
+    }
+
+    #[test]
+    fn empty_graph_gets_no_sample_queries() {
+        let queries = sample_queries(&GraphSchema::default());
+        assert!(!queries.contains("MATCH"));
+    }
+
+    #[test]
+    fn hashtag_node_queries_need_the_hashtag_nodes() {
+        let without_nodes = schema(&["Tweet", "User"], &["REPLIES_TO"], &["hashtags"]);
+        let queries = sample_queries(&without_nodes);
+        assert!(queries.contains("UNWIND t.hashtags"));
+        assert!(!queries.contains(":Hashtag"));
+        assert!(!queries.contains("t.location"));
+        assert!(!queries.contains("edit_history"));
+        assert!(!queries.contains("raw_json"));
+
+        let with_nodes = schema(&["Tweet", "Hashtag"], &["TAGGED_WITH"], &[]);
+        let queries = sample_queries(&with_nodes);
+        assert!(queries.contains("(h:Hashtag)"));
+        assert!(!queries.contains("UNWIND t.hashtags"));
+    }
+
+    #[test]
+    fn full_graph_gets_every_sample_query() {
+        let full = schema(
+            &["Tweet", "User", "Hashtag", "Airline"],
+            &["REPLIES_TO", "MENTIONS", "POSTED_BY", "TAGGED_WITH"],
+            &[
+                "hashtags",
+                "followers_count",
+                "lang",
+                "location",
+                "edit_history",
+                "is_original",
+                "raw_json",
+            ],
+        );
+        let queries = sample_queries(&full);
+        for sample in &SAMPLE_QUERIES {
+            assert!(queries.contains(sample.cypher));
+        }
+    }
+
+    #[test]
+    fn tweet_literals_leave_out_nulls_and_sort_the_keys() {
+        let literal = tweet_to_cypher_literal(&HashMap::from(&tweet()));
+        assert!(literal.starts_with("{`cashtags`: [], `created_at`: '2019-05-23T14:54:46+00:00'"));
+        assert!(!literal.contains("null"));
+        assert!(!literal.contains("`reply_to`"));
+    }
+
+    #[test]
+    fn small_batches_replace_the_tweets_parameter() {
+        let tweets = vec![HashMap::from(&tweet())];
+        let cypher = "UNWIND $tweets AS tweet MERGE (t:Tweet {id: tweet.id}) RETURN $runId";
+        let inlined = inline_tweets(cypher, &tweets);
+        assert!(!inlined.contains("$tweets"));
+        assert!(inlined.contains("$runId"));
+        assert!(inlined.starts_with(&format!(
+            "UNWIND [{}] AS tweet",
+            tweet_to_cypher_literal(&tweets[0])
+        )));
+    }
+
+    // The text and hashtags of a tweet are under the control of whoever posts
+    // it, so they must never reach the query outside a string
+    mod cypher_injection {
+        use super::*;
+        use proptest::prelude::*;
+
+        // The quality score depends on the text, so it is pinned to compare
+        // the queries of two tweets
+        fn params_with(text: &str, hashtags: Vec<String>) -> HashMap<String, BoltType> {
+            let mut tweet = tweet();
+            tweet.text = text.to_owned();
+            tweet.entities.hashtags = hashtags;
+            let mut params = HashMap::from(&tweet);
+            params.insert("qualityScore".to_string(), 0.5.into());
+            params
+        }
+
+        proptest! {
+            #[test]
+            fn tweet_strings_only_change_the_string_literals(
+                text in any::<String>(),
+                hashtags in prop::collection::vec(any::<String>(), 0..4),
+            ) {
+                let placeholders = vec!["x".to_owned(); hashtags.len()];
+                let reference = params_with("x", placeholders);
+                let params = params_with(&text, hashtags.clone());
+
+                let cypher = QueryBuilder::new(&InsertOptions::default()).build_insert_query();
+                let (expected, _) = split_cypher_strings(&inline_tweets(&cypher, &[reference]));
+                let (skeleton, strings) = split_cypher_strings(&inline_tweets(&cypher, &[params]));
+                prop_assert_eq!(skeleton, expected);
+                prop_assert!(strings.contains(&text));
+                for hashtag in &hashtags {
+                    prop_assert!(strings.contains(hashtag));
+                }
+            }
+
+            #[test]
+            fn any_string_round_trips_through_cypher_literal(value in any::<String>()) {
+                prop_assert_eq!(read_cypher_string(&cypher_literal(&BoltType::from(value.as_str()))), value);
+            }
+        }
+    }
+
+    mod with_mock_graph {
+        use super::*;
+        use crate::db::mock_db::{Call, MockGraph};
+
+        fn batch(len: usize) -> BatchParameters {
+            let tweets = (0..len)
+                .map(|i| {
+                    let mut tweet = tweet();
+                    tweet.id_str = i.to_string();
+                    tweet.text = format!("it's tweet {}", i);
+                    tweet
+                })
+                .collect();
+            prepare_batch_parameters(tweets, &InsertOptions::default())
+        }
+
+        fn deadlock() -> neo4rs::Error {
+            neo4rs::Error::UnexpectedMessage(
+                "Neo.TransientError.Transaction.DeadlockDetected".to_string(),
+            )
+        }
+
+        fn options(max_retries: Option<u32>) -> InsertOptions {
+            InsertOptions {
+                retry: RetryConfig {
+                    max_retries,
+                    ..RetryConfig::default()
+                },
+                ..InsertOptions::default()
+            }
+        }
+
+        fn only_txn(graph: &MockGraph) -> (Vec<Statement>, bool) {
+            match graph.calls().as_slice() {
+                [
+                    Call::Txn {
+                        statements,
+                        rollback,
+                    },
+                ] => (statements.clone(), *rollback),
+                calls => panic!("expected one transaction, got {:?}", calls),
+            }
+        }
+
+        fn has_param(statement: &Statement, key: &str) -> bool {
+            statement.params.iter().any(|(name, _)| name == key)
+        }
+
+        #[tokio::test]
+        async fn small_batches_are_inlined_after_the_users() {
+            let graph = MockGraph::default();
+            graph.push_txn_result(Ok(Some(3)));
+            let txn_id = Uuid::new_v4();
+            let inserted = run_insert_with_txn(&graph, batch(3), txn_id, &InsertOptions::default())
+                .await
+                .unwrap();
+            assert_eq!(inserted, 3);
+
+            let (statements, rollback) = only_txn(&graph);
+            assert!(!rollback);
+            let [users, tweets] = statements.as_slice() else {
+                panic!(
+                    "expected the users and tweets queries, got {:?}",
+                    statements
+                );
+            };
+            let comment = format!("// txn: {}", txn_id);
+            assert!(users.cypher.starts_with(&comment));
+            assert!(users.cypher.contains("UNWIND $users AS user"));
+            assert!(has_param(users, "users"));
+            assert!(tweets.cypher.starts_with(&comment));
+            assert!(tweets.cypher.contains("UNWIND [{"));
+            assert!(tweets.cypher.contains("'it\\'s tweet 2'"));
+            assert!(!has_param(tweets, "tweets"));
+            assert!(has_param(tweets, "runId"));
+        }
+
+        #[tokio::test]
+        async fn larger_batches_send_the_tweets_as_a_parameter() {
+            let graph = MockGraph::default();
+            graph.push_txn_result(Ok(Some(INLINE_BATCH_SIZE as i64)));
+            let inserted = run_insert_with_txn(
+                &graph,
+                batch(INLINE_BATCH_SIZE),
+                Uuid::new_v4(),
+                &InsertOptions::default(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(inserted, INLINE_BATCH_SIZE);
+
+            let (statements, _) = only_txn(&graph);
+            assert!(statements[1].cypher.contains("UNWIND $tweets AS tweet"));
+            assert!(!statements[1].cypher.contains("it\\'s"));
+            assert!(has_param(&statements[1], "tweets"));
+        }
+
+        #[tokio::test]
+        async fn dry_run_db_explains_and_rolls_back() {
+            let graph = MockGraph::default();
+            let options = InsertOptions {
+                dry_run_db: true,
+                ..InsertOptions::default()
+            };
+            let inserted = run_insert_with_txn(&graph, batch(4), Uuid::new_v4(), &options)
+                .await
+                .unwrap();
+            // EXPLAIN returns no rows, the whole batch counts as validated
+            assert_eq!(inserted, 4);
+
+            let (statements, rollback) = only_txn(&graph);
+            assert!(rollback);
+            for statement in &statements {
+                assert_eq!(statement.cypher.lines().nth(1), Some("EXPLAIN"));
+            }
+        }
+
+        #[tokio::test]
+        async fn deadlocks_are_retried_in_a_new_transaction() {
+            let graph = MockGraph::default();
+            graph.push_txn_result(Err(deadlock()));
+            graph.push_txn_result(Ok(Some(2)));
+            let retries = AtomicU64::new(0);
+            let outcome =
+                insert_batch_with_retry(&graph, batch(2), 0, &options(None), &retries).await;
+
+            assert!(matches!(outcome, BatchOutcome::Inserted(2)));
+            assert_eq!(retries.load(Ordering::Relaxed), 1);
+            assert_eq!(graph.calls().len(), 2);
+            // Each attempt has its own transaction id
+            assert_ne!(
+                graph.cyphers()[0].lines().next(),
+                graph.cyphers()[2].lines().next()
+            );
+        }
+
+        #[tokio::test]
+        async fn deadlocks_stop_being_retried_after_max_retries() {
+            let graph = MockGraph::default();
+            graph.push_txn_result(Err(deadlock()));
+            graph.push_txn_result(Ok(Some(2)));
+            let retries = AtomicU64::new(0);
+            let outcome =
+                insert_batch_with_retry(&graph, batch(2), 0, &options(Some(0)), &retries).await;
+
+            assert!(matches!(outcome, BatchOutcome::Failed));
+            assert_eq!(retries.load(Ordering::Relaxed), 0);
+            assert_eq!(graph.calls().len(), 1);
+        }
+
+        #[tokio::test]
+        async fn other_errors_fail_the_batch_at_once() {
+            let graph = MockGraph::default();
+            graph.push_txn_result(Err(neo4rs::Error::UnexpectedMessage(
+                "Neo.ClientError.Statement.SyntaxError".to_string(),
+            )));
+            let retries = AtomicU64::new(0);
+            let outcome =
+                insert_batch_with_retry(&graph, batch(2), 0, &options(None), &retries).await;
+
+            assert!(matches!(outcome, BatchOutcome::Failed));
+            assert_eq!(graph.calls().len(), 1);
+        }
+
+        #[test]
+        fn transient_errors_are_classified_by_their_code() {
+            let classify = |message: &str, mode| {
+                classify_transient_error(
+                    &neo4rs::Error::UnexpectedMessage(message.to_string()),
+                    mode,
+                )
+            };
+            assert_eq!(
+                classify(
+                    "Neo.TransientError.Transaction.DeadlockDetected",
+                    ImportMode::Merge
+                ),
+                Some(TransientErrorKind::Deadlock)
+            );
+            assert_eq!(
+                classify(
+                    "Neo.TransientError.Transaction.Terminated",
+                    ImportMode::Merge
+                ),
+                Some(TransientErrorKind::TransactionTerminated)
+            );
+            assert_eq!(
+                classify(
+                    "Neo.TransientError.General.OutOfMemoryError",
+                    ImportMode::Merge
+                ),
+                Some(TransientErrorKind::Unknown)
+            );
+            // A concurrent create only happens in `create` mode
+            let duplicate = "Neo.ClientError.Schema.ConstraintValidationFailed";
+            assert_eq!(
+                classify(duplicate, ImportMode::Create),
+                Some(TransientErrorKind::ConcurrentCreate)
+            );
+            assert_eq!(classify(duplicate, ImportMode::Merge), None);
+            assert_eq!(
+                classify_transient_error(&neo4rs::Error::ConnectionError, ImportMode::Merge),
+                Some(TransientErrorKind::NetworkFailure)
+            );
+        }
+
+        #[tokio::test]
+        async fn constraints_are_created_before_the_indexes() {
+            let graph = MockGraph::default();
+            graph.push_count(Ok(UNIQUE_CONSTRAINTS.len() as i64));
+            create_schema(&graph, SemVer::new(5, 20, 0), Duration::ZERO)
+                .await
+                .unwrap();
+
+            let cyphers = graph.cyphers();
+            let mut expected: Vec<String> = UNIQUE_CONSTRAINTS
+                .iter()
+                .map(|constraint| constraint.create_ddl(SemVer::new(5, 20, 0)))
+                .collect();
+            expected.extend(
+                PROPERTY_INDEXES
+                    .iter()
+                    .map(|index| index.create_ddl(SemVer::new(5, 20, 0))),
+            );
+            assert_eq!(cyphers[..expected.len()], expected);
+            assert!(cyphers[expected.len()].starts_with("SHOW CONSTRAINTS"));
+            assert_eq!(cyphers.len(), expected.len() + 1);
+        }
+
+        #[tokio::test]
+        async fn schema_wait_polls_until_the_constraints_are_applied() {
+            let graph = MockGraph::default();
+            graph.push_count(Ok(0));
+            graph.push_count(Ok(UNIQUE_CONSTRAINTS.len() as i64));
+            create_schema(&graph, SemVer::DEFAULT, Duration::from_secs(5))
+                .await
+                .unwrap();
+
+            let polls = graph
+                .calls()
+                .into_iter()
+                .filter(|call| matches!(call, Call::FetchCount(_)))
+                .count();
+            assert_eq!(polls, 2);
+        }
+    }
 }
@@ -0,0 +1,97 @@
+use std::future::Future;
+
+use neo4rs::{BoltType, Graph};
+
+// A query with its parameters, kept as text until it is sent so that tests can
+// read back what would have been run
+#[derive(Debug, Clone)]
+pub struct Statement {
+    pub cypher: String,
+    pub params: Vec<(String, BoltType)>,
+}
+
+impl Statement {
+    pub fn new(cypher: String) -> Self {
+        Self {
+            cypher,
+            params: Vec::new(),
+        }
+    }
+
+    pub fn param(mut self, key: &str, value: impl Into<BoltType>) -> Self {
+        self.params.push((key.to_owned(), value.into()));
+        self
+    }
+
+    fn into_query(self) -> neo4rs::Query {
+        self.params
+            .into_iter()
+            .fold(neo4rs::query(&self.cypher), |query, (key, value)| {
+                query.param(&key, value)
+            })
+    }
+}
+
+// The calls to Neo4j made by the insert and schema steps, so that they can run
+// against MockGraph in tests. The futures are Send because the batches are
+// inserted from spawned tasks
+pub trait GraphClient: Clone + Send + Sync + 'static {
+    // Runs the statement in its own auto-committed transaction
+    fn run(&self, statement: Statement) -> impl Future<Output = Result<(), neo4rs::Error>> + Send;
+
+    // Runs a statement returning a single `count` column
+    fn fetch_count(
+        &self,
+        statement: Statement,
+    ) -> impl Future<Output = Result<i64, neo4rs::Error>> + Send;
+
+    // Runs the statements in one transaction, rolled back instead of committed
+    // when `rollback` is set. Returns `column` of the last row of the last
+    // statement
+    fn run_in_txn(
+        &self,
+        statements: Vec<Statement>,
+        column: &'static str,
+        rollback: bool,
+    ) -> impl Future<Output = Result<Option<i64>, neo4rs::Error>> + Send;
+}
+
+impl GraphClient for Graph {
+    async fn run(&self, statement: Statement) -> Result<(), neo4rs::Error> {
+        Graph::run(self, statement.into_query()).await
+    }
+
+    async fn fetch_count(&self, statement: Statement) -> Result<i64, neo4rs::Error> {
+        let mut result = self.execute(statement.into_query()).await?;
+        Ok(match result.next().await? {
+            Some(row) => row.get::<i64>("count").unwrap_or(0),
+            None => 0,
+        })
+    }
+
+    async fn run_in_txn(
+        &self,
+        mut statements: Vec<Statement>,
+        column: &'static str,
+        rollback: bool,
+    ) -> Result<Option<i64>, neo4rs::Error> {
+        let mut txn = self.start_txn().await?;
+        let last = statements.pop();
+        for statement in statements {
+            txn.run(statement.into_query()).await?;
+        }
+        let mut value = None;
+        if let Some(last) = last {
+            let mut result = txn.execute(last.into_query()).await?;
+            while let Some(row) = result.next(txn.handle()).await? {
+                value = row.get::<i64>(column).ok();
+            }
+        }
+        if rollback {
+            txn.rollback().await?;
+        } else {
+            txn.commit().await?;
+        }
+        Ok(value)
+    }
+}
@@ -0,0 +1,82 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use super::graph_client::{GraphClient, Statement};
+
+// What MockGraph was asked to do, in order
+#[derive(Debug, Clone)]
+pub enum Call {
+    Run(Statement),
+    FetchCount(Statement),
+    Txn {
+        statements: Vec<Statement>,
+        rollback: bool,
+    },
+}
+
+#[derive(Default)]
+struct State {
+    calls: Vec<Call>,
+    counts: VecDeque<Result<i64, neo4rs::Error>>,
+    txn_results: VecDeque<Result<Option<i64>, neo4rs::Error>>,
+}
+
+// Records the statements sent to it and answers with the results queued by the
+// test. Once a queue is empty, counts are 0 and transactions return no row
+#[derive(Clone, Default)]
+pub struct MockGraph {
+    state: Arc<Mutex<State>>,
+}
+
+impl MockGraph {
+    pub fn push_count(&self, result: Result<i64, neo4rs::Error>) {
+        self.state.lock().unwrap().counts.push_back(result);
+    }
+
+    pub fn push_txn_result(&self, result: Result<Option<i64>, neo4rs::Error>) {
+        self.state.lock().unwrap().txn_results.push_back(result);
+    }
+
+    pub fn calls(&self) -> Vec<Call> {
+        self.state.lock().unwrap().calls.clone()
+    }
+
+    // Cypher of every statement, in the order they were sent
+    pub fn cyphers(&self) -> Vec<String> {
+        self.calls()
+            .into_iter()
+            .flat_map(|call| match call {
+                Call::Run(statement) | Call::FetchCount(statement) => vec![statement],
+                Call::Txn { statements, .. } => statements,
+            })
+            .map(|statement| statement.cypher)
+            .collect()
+    }
+}
+
+impl GraphClient for MockGraph {
+    async fn run(&self, statement: Statement) -> Result<(), neo4rs::Error> {
+        self.state.lock().unwrap().calls.push(Call::Run(statement));
+        Ok(())
+    }
+
+    async fn fetch_count(&self, statement: Statement) -> Result<i64, neo4rs::Error> {
+        let mut state = self.state.lock().unwrap();
+        state.calls.push(Call::FetchCount(statement));
+        state.counts.pop_front().unwrap_or(Ok(0))
+    }
+
+    async fn run_in_txn(
+        &self,
+        statements: Vec<Statement>,
+        _column: &'static str,
+        rollback: bool,
+    ) -> Result<Option<i64>, neo4rs::Error> {
+        let mut state = self.state.lock().unwrap();
+        state.calls.push(Call::Txn {
+            statements,
+            rollback,
+        });
+        state.txn_results.pop_front().unwrap_or(Ok(None))
+    }
+}
@@ -0,0 +1,345 @@
+use super::{FieldSet, ImportMode, InsertOptions, TweetField};
+
+// Assembles the insert queries of a batch from one fragment per optional
+// feature, so that a new node or relation type only adds a fragment
+pub struct QueryBuilder<'a> {
+    import_mode: ImportMode,
+    fields: &'a FieldSet,
+    track_runs: bool,
+}
+
+impl<'a> QueryBuilder<'a> {
+    pub fn new(options: &'a InsertOptions) -> Self {
+        Self {
+            import_mode: options.import_mode,
+            fields: &options.fields,
+            track_runs: options.run_id.is_some(),
+        }
+    }
+
+    // Merges the distinct users of a batch first, so popular users are
+    // locked once per batch instead of once per tweet
+    // listed_count -> on how many lists they are
+    // favourites_count -> how many tweets they liked in lifetime
+    // statuses_count -> how many tweets they posted
+    pub fn build_users_query(&self) -> String {
+        let properties: Vec<String> = self
+            .fields
+            .user_fields()
+            .map(|field| format!("u.{0} = user.{0}", field.property()))
+            .collect();
+        let mut cypher = "
+            UNWIND $users AS user
+            MERGE (u:User {id: user.id})"
+            .to_owned();
+        if !properties.is_empty() {
+            cypher.push_str(&format!(
+                "
+            ON CREATE SET
+                {}",
+                properties.join(",\n                ")
+            ));
+        }
+        cypher
+    }
+
+    pub fn build_insert_query(&self) -> String {
+        [
+            self.author(),
+            self.tweet(),
+            self.properties(),
+            self.engagement_counts(),
+            self.stored_json(),
+            self.import_runs(),
+            self.coordinates(),
+            self.scopes(),
+            self.posted_by(),
+            self.inserted_count(),
+        ]
+        .concat()
+    }
+
+    // The authors were merged once each by the users query of the same
+    // transaction, so they are only matched here
+    fn author(&self) -> String {
+        "
+            UNWIND $tweets AS tweet
+            MATCH (u:User {id: tweet.userId})"
+            .to_owned()
+    }
+
+    fn tweet(&self) -> String {
+        let clause = match self.import_mode {
+            ImportMode::Merge => "MERGE (t:Tweet {id: tweet.id})",
+            // The tweets that already exist are left out, and counted as
+            // duplicates from the number of tweets returned
+            ImportMode::Create => {
+                "OPTIONAL MATCH (existing:Tweet {id: tweet.id})
+            WITH tweet, u
+            WHERE existing IS NULL
+            CREATE (t:Tweet {id: tweet.id})"
+            }
+            // The runs that imported the old node are kept for purge_import
+            ImportMode::Replace => {
+                "OPTIONAL MATCH (old:Tweet {id: tweet.id})
+            WITH tweet, u, old, old.import_runs AS importRuns
+            DETACH DELETE old
+            WITH tweet, u, importRuns
+            CREATE (t:Tweet {id: tweet.id})
+            SET t.import_runs = importRuns"
+            }
+        };
+        format!("\n            {}", clause)
+    }
+
+    // Fields stored as they are, the others have a fragment of their own
+    fn properties(&self) -> String {
+        let properties: Vec<String> = self
+            .fields
+            .tweet_fields()
+            .filter(|field| {
+                !matches!(
+                    field,
+                    TweetField::Location
+                        | TweetField::Scopes
+                        | TweetField::RetweetCount
+                        | TweetField::FavoriteCount
+                )
+            })
+            .map(|field| format!("t.{0} = tweet.{0}", field.property()))
+            .collect();
+        if properties.is_empty() {
+            return String::new();
+        }
+        format!(
+            "
+            SET
+                {}",
+            properties.join(",\n                ")
+        )
+    }
+
+    // Counts captured at different times are merged by keeping the highest,
+    // so that re-importing an older capture does not decrease them
+    fn engagement_counts(&self) -> String {
+        [TweetField::RetweetCount, TweetField::FavoriteCount]
+            .into_iter()
+            .filter(|field| self.fields.tweet_fields.contains(field))
+            .map(|field| {
+                format!(
+                    "
+            SET t.{0} = CASE
+                WHEN t.{0} IS NULL OR tweet.{0} > t.{0} THEN tweet.{0}
+                ELSE t.{0}
+            END",
+                    field.property()
+                )
+            })
+            .collect()
+    }
+
+    fn stored_json(&self) -> String {
+        "
+            SET t.quality_score = tweet.qualityScore
+            FOREACH (r IN CASE WHEN tweet.rawJson IS NOT NULL THEN [1] ELSE [] END |
+                SET t.raw_json = tweet.rawJson
+            )
+            FOREACH (e IN CASE WHEN tweet.extraJson IS NOT NULL THEN [1] ELSE [] END |
+                SET t.extra_json = tweet.extraJson
+            )"
+        .to_owned()
+    }
+
+    // Ids of the runs that imported the tweet, so that a run can be purged
+    // without losing the tweets another run imported too
+    fn import_runs(&self) -> String {
+        if !self.track_runs {
+            return String::new();
+        }
+        "
+            SET t.import_runs = CASE
+                WHEN $runId IN coalesce(t.import_runs, []) THEN t.import_runs
+                ELSE coalesce(t.import_runs, []) + $runId
+            END"
+        .to_owned()
+    }
+
+    fn coordinates(&self) -> String {
+        if !self.fields.tweet_fields.contains(&TweetField::Location) {
+            return String::new();
+        }
+        "
+            FOREACH (c IN CASE WHEN tweet.longitude IS NOT NULL THEN [1] ELSE [] END |
+                SET t.location = point({
+                    longitude: tweet.longitude,
+                    latitude: tweet.latitude,
+                    srid: 4326
+                })
+            )"
+        .to_owned()
+    }
+
+    fn scopes(&self) -> String {
+        if !self.fields.tweet_fields.contains(&TweetField::Scopes) {
+            return String::new();
+        }
+        "
+            FOREACH (placeId IN coalesce(tweet.scopePlaceIds, []) |
+                MERGE (p:Place {id: placeId})
+                MERGE (t)-[:SCOPED_TO]->(p)
+            )"
+        .to_owned()
+    }
+
+    fn posted_by(&self) -> String {
+        "
+            MERGE (t)-[:POSTED_BY]->(u)"
+            .to_owned()
+    }
+
+    // Read by run_insert_with_txn, one row per tweet written
+    fn inserted_count(&self) -> String {
+        "
+            RETURN count(t) AS inserted
+            "
+        .to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::UserField;
+    use clap::ValueEnum;
+
+    fn insert_query(import_mode: ImportMode) -> String {
+        let options = InsertOptions {
+            import_mode,
+            ..Default::default()
+        };
+        QueryBuilder::new(&options).build_insert_query()
+    }
+
+    #[test]
+    fn merge_mode_updates_existing_tweets() {
+        let cypher = insert_query(ImportMode::Merge);
+        assert!(cypher.contains("MERGE (t:Tweet {id: tweet.id})"));
+        assert!(!cypher.contains("CREATE (t:Tweet"));
+        assert!(!cypher.contains("DETACH DELETE"));
+    }
+
+    #[test]
+    fn create_mode_skips_existing_tweets() {
+        let cypher = insert_query(ImportMode::Create);
+        let filter = cypher.find("WHERE existing IS NULL").unwrap();
+        let create = cypher.find("CREATE (t:Tweet {id: tweet.id})").unwrap();
+        assert!(filter < create);
+        assert!(!cypher.contains("DETACH DELETE"));
+        assert!(cypher.trim_end().ends_with("RETURN count(t) AS inserted"));
+    }
+
+    #[test]
+    fn replace_mode_keeps_the_import_runs() {
+        let cypher = insert_query(ImportMode::Replace);
+        let saved = cypher.find("old.import_runs AS importRuns").unwrap();
+        let delete = cypher.find("DETACH DELETE old").unwrap();
+        let restored = cypher.find("SET t.import_runs = importRuns").unwrap();
+        assert!(saved < delete && delete < restored);
+    }
+
+    #[test]
+    fn posted_by_is_merged_so_retries_do_not_duplicate_it() {
+        for mode in [ImportMode::Merge, ImportMode::Create, ImportMode::Replace] {
+            let cypher = insert_query(mode);
+            assert!(cypher.contains("MERGE (t)-[:POSTED_BY]->(u)"));
+            assert!(!cypher.contains("CREATE (t)-[:POSTED_BY]"));
+        }
+    }
+
+    // Brackets of every kind are closed in order. The fragments hold no string
+    // literals, so every bracket counts
+    fn assert_balanced(cypher: &str) {
+        let mut open = Vec::new();
+        for c in cypher.chars() {
+            match c {
+                '(' | '[' | '{' => open.push(c),
+                ')' => assert_eq!(open.pop(), Some('('), "{}", cypher),
+                ']' => assert_eq!(open.pop(), Some('['), "{}", cypher),
+                '}' => assert_eq!(open.pop(), Some('{'), "{}", cypher),
+                _ => (),
+            }
+        }
+        assert!(open.is_empty(), "{}", cypher);
+    }
+
+    #[test]
+    fn every_flag_combination_builds_a_well_formed_query() {
+        let optional = [
+            TweetField::Location,
+            TweetField::Scopes,
+            TweetField::RetweetCount,
+            TweetField::FavoriteCount,
+        ];
+        for mask in 0..1 << optional.len() {
+            let selected: Vec<TweetField> = (0..optional.len())
+                .filter(|i| mask & (1 << i) != 0)
+                .map(|i| optional[i])
+                .collect();
+            for track_runs in [false, true] {
+                for &import_mode in ImportMode::value_variants() {
+                    let mut fields = FieldSet::default();
+                    fields
+                        .tweet_fields
+                        .retain(|field| !optional.contains(field) || selected.contains(field));
+                    let options = InsertOptions {
+                        import_mode,
+                        fields,
+                        run_id: track_runs.then(|| "run".to_owned()),
+                        ..Default::default()
+                    };
+                    let cypher = QueryBuilder::new(&options).build_insert_query();
+
+                    assert!(cypher.trim_start().starts_with("UNWIND $tweets AS tweet"));
+                    assert!(cypher.trim_end().ends_with("RETURN count(t) AS inserted"));
+                    assert_balanced(&cypher);
+                    let has = |field| selected.contains(&field);
+                    assert_eq!(
+                        cypher.contains("SET t.location = point("),
+                        has(TweetField::Location)
+                    );
+                    assert_eq!(cypher.contains("[:SCOPED_TO]"), has(TweetField::Scopes));
+                    assert_eq!(
+                        cypher.contains("SET t.retweet_count = CASE"),
+                        has(TweetField::RetweetCount)
+                    );
+                    assert_eq!(
+                        cypher.contains("SET t.favorite_count = CASE"),
+                        has(TweetField::FavoriteCount)
+                    );
+                    assert_eq!(cypher.contains("$runId"), track_runs);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn queries_without_optional_properties_have_no_empty_set() {
+        let options = InsertOptions {
+            fields: FieldSet {
+                tweet_fields: [TweetField::Id].into_iter().collect(),
+                user_fields: [UserField::Id].into_iter().collect(),
+            },
+            ..Default::default()
+        };
+        let builder = QueryBuilder::new(&options);
+
+        let cypher = builder.build_insert_query();
+        assert_balanced(&cypher);
+        assert!(!cypher.contains("SET\n"));
+        assert!(!cypher.contains("t.text"));
+
+        let users = builder.build_users_query();
+        assert!(!users.contains("ON CREATE SET"));
+        assert!(users.contains("MERGE (u:User {id: user.id})"));
+    }
+}
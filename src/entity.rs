@@ -0,0 +1,79 @@
+use reqwest::header::LOCATION;
+use reqwest::redirect::Policy;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::time::{Interval, MissedTickBehavior};
+
+use crate::error::PipelineError;
+use crate::json::{Tweet, UrlEntity};
+
+// Twitter rate limits clients that resolve more t.co links than this
+const REQUESTS_PER_SEC: u64 = 100;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Resolves the t.co links that older API responses leave without an
+// `expanded_url`, by reading the redirect of a HEAD request
+pub struct TcoResolver {
+    client: reqwest::Client,
+    ticker: Interval,
+    // The same link is often shared by many retweets. None when t.co did not
+    // redirect, so that the link is not requested again
+    cache: HashMap<String, Option<String>>,
+}
+
+impl TcoResolver {
+    pub fn new() -> Result<Self, PipelineError> {
+        let mut ticker = tokio::time::interval(Duration::from_millis(1000 / REQUESTS_PER_SEC));
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Ok(Self {
+            client: reqwest::Client::builder()
+                .redirect(Policy::none())
+                .timeout(REQUEST_TIMEOUT)
+                .build()?,
+            ticker,
+            cache: HashMap::new(),
+        })
+    }
+
+    pub async fn expand_tco_urls(&mut self, tweets: &mut [Tweet]) {
+        for tweet in tweets {
+            self.resolve_tco_urls(&mut tweet.entities.urls).await;
+        }
+    }
+
+    // A link that cannot be requested is logged and left unexpanded. It is not
+    // cached, as the failure may be temporary
+    pub async fn resolve_tco_urls(&mut self, entities: &mut [UrlEntity]) {
+        for entity in entities {
+            if entity.expanded_url.is_some() || !is_tco_url(&entity.url) {
+                continue;
+            }
+            if let Some(expanded) = self.cache.get(&entity.url) {
+                entity.expanded_url = expanded.clone();
+                continue;
+            }
+
+            self.ticker.tick().await;
+            let response = match self.client.head(&entity.url).send().await {
+                Ok(response) => response,
+                Err(e) => {
+                    eprintln!("Could not expand {}: {}", entity.url, e);
+                    continue;
+                }
+            };
+            let expanded = response
+                .status()
+                .is_redirection()
+                .then(|| response.headers().get(LOCATION))
+                .flatten()
+                .and_then(|location| location.to_str().ok())
+                .map(str::to_owned);
+            self.cache.insert(entity.url.clone(), expanded.clone());
+            entity.expanded_url = expanded;
+        }
+    }
+}
+
+fn is_tco_url(url: &str) -> bool {
+    url.starts_with("https://t.co/") || url.starts_with("http://t.co/")
+}
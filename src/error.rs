@@ -0,0 +1,73 @@
+use std::fmt;
+
+#[derive(Debug)]
+pub enum PipelineError {
+    Database(neo4rs::Error),
+    // Connecting to Neo4j failed for a known reason, with a hint for the user
+    Connection(String),
+    UnexpectedResult(String),
+    Csv(csv::Error),
+    Io(std::io::Error),
+    Http(reqwest::Error),
+    // `--on-error abort` stopped at this parse error
+    ParseAborted(String),
+    // A stage stopped the import, on input it cannot use or a graph that
+    // failed a check
+    Aborted(String),
+    #[cfg(feature = "parquet")]
+    Parquet(parquet::errors::ParquetError),
+}
+
+impl fmt::Display for PipelineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PipelineError::Database(e) => write!(f, "Database error: {}", e),
+            PipelineError::Connection(msg) => write!(f, "{}", msg),
+            PipelineError::UnexpectedResult(msg) => write!(f, "Unexpected query result: {}", msg),
+            PipelineError::Csv(e) => write!(f, "CSV error: {}", e),
+            PipelineError::Io(e) => write!(f, "I/O error: {}", e),
+            PipelineError::Http(e) => write!(f, "HTTP error: {}", e),
+            PipelineError::ParseAborted(location) => write!(
+                f,
+                "Stopped at the parse error in {}, as set by --on-error abort",
+                location
+            ),
+            PipelineError::Aborted(reason) => write!(f, "{}", reason),
+            #[cfg(feature = "parquet")]
+            PipelineError::Parquet(e) => write!(f, "Parquet error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for PipelineError {}
+
+impl From<neo4rs::Error> for PipelineError {
+    fn from(e: neo4rs::Error) -> Self {
+        PipelineError::Database(e)
+    }
+}
+
+impl From<std::io::Error> for PipelineError {
+    fn from(e: std::io::Error) -> Self {
+        PipelineError::Io(e)
+    }
+}
+
+impl From<reqwest::Error> for PipelineError {
+    fn from(e: reqwest::Error) -> Self {
+        PipelineError::Http(e)
+    }
+}
+
+impl From<csv::Error> for PipelineError {
+    fn from(e: csv::Error) -> Self {
+        PipelineError::Csv(e)
+    }
+}
+
+#[cfg(feature = "parquet")]
+impl From<parquet::errors::ParquetError> for PipelineError {
+    fn from(e: parquet::errors::ParquetError) -> Self {
+        PipelineError::Parquet(e)
+    }
+}
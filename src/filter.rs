@@ -0,0 +1,35 @@
+use std::collections::HashSet;
+
+use crate::db::UnicodeNormForm;
+use crate::json::Tweet;
+
+// Keeps the tweets using at least one of the configured hashtags. Hashtags are
+// compared case insensitively, after the normalization applied on import
+pub struct HashtagFilter {
+    tags: HashSet<String>,
+    normalization: UnicodeNormForm,
+}
+
+impl HashtagFilter {
+    pub fn new(tags: &[String], normalization: UnicodeNormForm) -> Self {
+        Self {
+            tags: tags
+                .iter()
+                .map(|tag| key(tag.trim_start_matches('#'), normalization))
+                .collect(),
+            normalization,
+        }
+    }
+
+    pub fn matches(&self, tweet: &Tweet) -> bool {
+        tweet
+            .entities
+            .hashtags
+            .iter()
+            .any(|tag| self.tags.contains(&key(tag, self.normalization)))
+    }
+}
+
+fn key(tag: &str, normalization: UnicodeNormForm) -> String {
+    normalization.apply(tag).to_lowercase()
+}
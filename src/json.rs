@@ -1,16 +1,27 @@
 use chrono::{DateTime, Utc};
-use lines;
-use lines::linereader::LineReader;
-use serde::Deserialize;
-use serde_json;
+use clap::ValueEnum;
+use memmap2::Mmap;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::ops::AddAssign;
+use std::path::{Path, PathBuf};
 use std::str::from_utf8;
+use std::time::{Duration, Instant};
 
-#[derive(Debug, Deserialize, Clone)]
+// Serializing gives back the v1.1 field names, but not the exact v1.1 format:
+// dates are written in ISO 8601 instead of the Twitter format, entities as
+// plain string arrays, coordinates as a bare [longitude, latitude] pair and
+// missing optional fields as null
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct User {
     pub id_str: String,
     pub screen_name: String,
     pub location: Option<String>,
+    // Dropped from newer v1.1 payloads in favour of verified_type
+    #[serde(default)]
     pub verified: bool,
     pub followers_count: i32,
     pub friends_count: i32,
@@ -20,66 +31,920 @@ pub struct User {
     #[serde(deserialize_with = "deserialize_twitter_date")]
     pub created_at: DateTime<Utc>,
     pub utc_offset: Option<i32>,
+    pub protected: Option<bool>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Tweet {
     #[serde(deserialize_with = "deserialize_twitter_date")]
     pub created_at: DateTime<Utc>,
     pub id_str: String,
-    #[serde(skip)]
+    // Filled from text or full_text by extract_text
+    #[serde(skip_deserializing)]
     pub text: String,
     pub user: User,
     #[serde(rename = "in_reply_to_status_id_str")]
     pub reply_to: Option<String>,
-    pub lang: String,
+    #[serde(rename = "in_reply_to_user_id_str", default)]
+    pub reply_to_user_id: Option<String>,
+    // null when Twitter could not detect the language
+    #[serde(default)]
+    pub lang: Option<String>,
+    // Missing from some API variants
+    #[serde(default)]
     pub entities: Entity,
     #[serde(default)]
     pub is_retweet: bool,
+    // [longitude, latitude] of geo-tagged tweets
+    #[serde(default, deserialize_with = "deserialize_coordinates")]
+    pub coordinates: Option<[f64; 2]>,
+    // Ids of every version of an edited tweet, the original first
+    #[serde(default)]
+    pub edit_history_tweet_ids: Option<Vec<String>>,
+    // Engagement when the tweet was captured, only grows between captures
+    #[serde(default)]
+    pub retweet_count: Option<i64>,
+    #[serde(default)]
+    pub favorite_count: Option<i64>,
+    // Places the tweet is meant for, which can differ from where it was posted
+    #[serde(default)]
+    pub scopes: Option<TweetScopes>,
+    // The line the tweet was read from, only kept with `--store-raw-json`
+    #[serde(skip)]
+    pub raw_json: Option<String>,
+    // Every field of the v1.1 object that is not mapped above
+    #[cfg(feature = "extra_fields")]
+    #[serde(flatten)]
+    pub extra: HashMap<String, serde_json::Value>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
 pub struct Entity {
-    #[serde(deserialize_with = "deserialize_hashtags")]
+    #[serde(default, deserialize_with = "deserialize_hashtags")]
     pub hashtags: Vec<String>,
-    #[serde(deserialize_with = "deserialize_user_mentions")]
+    #[serde(default, deserialize_with = "deserialize_user_mentions")]
     pub user_mentions: Vec<String>,
+    // Cashtags such as $AAPL, without the leading $
+    #[serde(default, deserialize_with = "deserialize_symbols")]
+    pub symbols: Vec<String>,
+    #[serde(default)]
+    pub urls: Vec<UrlEntity>,
+}
+
+// A link of the tweet text, `url` is the t.co short link
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct UrlEntity {
+    pub url: String,
+    #[serde(default)]
+    pub expanded_url: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+pub struct TweetScopes {
+    #[serde(default)]
+    pub place_ids: Vec<String>,
+}
+
+// One-line summary: "[2019-05-23 14:54] @screen_name: text..."
+impl fmt::Display for Tweet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const MAX_TEXT_CHARS: usize = 60;
+
+        let mut text: String = self.text.chars().take(MAX_TEXT_CHARS).collect();
+        if self.text.chars().count() > MAX_TEXT_CHARS {
+            text.push_str("...");
+        }
+        write!(
+            f,
+            "[{}] @{}: {}",
+            self.created_at.format("%Y-%m-%d %H:%M"),
+            self.user.screen_name,
+            text
+        )
+    }
+}
+
+impl fmt::Display for User {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn optional<T: fmt::Display>(value: &Option<T>) -> String {
+            value
+                .as_ref()
+                .map_or_else(|| "-".to_string(), |v| v.to_string())
+        }
+
+        writeln!(f, "{:<18}{}", "id:", self.id_str)?;
+        writeln!(f, "{:<18}@{}", "screen name:", self.screen_name)?;
+        writeln!(f, "{:<18}{}", "location:", optional(&self.location))?;
+        writeln!(f, "{:<18}{}", "verified:", self.verified)?;
+        writeln!(f, "{:<18}{}", "protected:", optional(&self.protected))?;
+        writeln!(f, "{:<18}{}", "followers:", self.followers_count)?;
+        writeln!(f, "{:<18}{}", "friends:", self.friends_count)?;
+        writeln!(f, "{:<18}{}", "listed:", optional(&self.listed_count))?;
+        writeln!(f, "{:<18}{}", "favourites:", self.favourites_count)?;
+        writeln!(f, "{:<18}{}", "statuses:", self.statuses_count)?;
+        writeln!(f, "{:<18}{}", "created at:", self.created_at)?;
+        write!(f, "{:<18}{}", "utc offset:", optional(&self.utc_offset))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum FormatVersion {
+    /// Twitter API v1.1, one tweet object per line
+    V1,
+    /// Twitter API v2, `data`/`includes` response objects per line
+    V2,
+    /// Sniff the format from the first line of every file
+    Auto,
+}
+
+// Executor the input files are parsed on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ParserExecutor {
+    /// Rayon thread pool, best for CPU-bound parsing of local files
+    Rayon,
+    /// One blocking Tokio task per file, lets slow reads from network storage
+    /// overlap without tying up the Rayon pool
+    Tokio,
+}
+
+// Twitter API v2 objects, only the fields the pipeline stores are mapped
+#[derive(Debug, Deserialize)]
+struct ResponseV2 {
+    #[serde(default, deserialize_with = "deserialize_one_or_many")]
+    data: Vec<TweetV2>,
+    #[serde(default)]
+    includes: IncludesV2,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct IncludesV2 {
+    #[serde(default)]
+    users: Vec<UserV2>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TweetV2 {
+    pub id: String,
+    pub text: String,
+    pub author_id: String,
+    pub created_at: DateTime<Utc>,
+    #[serde(default)]
+    pub lang: Option<String>,
+    #[serde(default)]
+    pub referenced_tweets: Vec<ReferencedTweetV2>,
+    #[serde(default)]
+    pub in_reply_to_user_id: Option<String>,
+    #[serde(default)]
+    pub edit_history_tweet_ids: Option<Vec<String>>,
+    #[serde(default)]
+    pub entities: EntitiesV2,
+    #[serde(default)]
+    pub public_metrics: Option<PublicMetricsV2>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PublicMetricsV2 {
+    pub retweet_count: i64,
+    pub like_count: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReferencedTweetV2 {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub id: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct EntitiesV2 {
+    #[serde(default)]
+    pub hashtags: Vec<HashtagV2>,
+    #[serde(default)]
+    pub mentions: Vec<MentionV2>,
+    #[serde(default)]
+    pub cashtags: Vec<HashtagV2>,
+    #[serde(default)]
+    pub urls: Vec<UrlEntity>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HashtagV2 {
+    pub tag: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MentionV2 {
+    pub id: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct UserV2 {
+    pub id: String,
+    pub username: String,
+    #[serde(default)]
+    pub location: Option<String>,
+    #[serde(default)]
+    pub verified: bool,
+    #[serde(default)]
+    pub protected: Option<bool>,
+    pub created_at: DateTime<Utc>,
+    #[serde(default)]
+    pub public_metrics: UserMetricsV2,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct UserMetricsV2 {
+    #[serde(default)]
+    pub followers_count: i32,
+    #[serde(default)]
+    pub following_count: i32,
+    #[serde(default)]
+    pub tweet_count: i32,
+    #[serde(default)]
+    pub listed_count: Option<i32>,
+    #[serde(default)]
+    pub like_count: i32,
 }
 
-pub fn parse_file(filename: String) -> (Vec<Tweet>, u32, u64, u32) {
+impl From<UserV2> for User {
+    fn from(user: UserV2) -> Self {
+        Self {
+            id_str: user.id,
+            screen_name: user.username,
+            location: user.location,
+            verified: user.verified,
+            followers_count: user.public_metrics.followers_count,
+            friends_count: user.public_metrics.following_count,
+            listed_count: user.public_metrics.listed_count,
+            favourites_count: user.public_metrics.like_count,
+            statuses_count: user.public_metrics.tweet_count,
+            created_at: user.created_at,
+            // v2 no longer exposes the user's timezone
+            utc_offset: None,
+            protected: user.protected,
+        }
+    }
+}
+
+impl TweetV2 {
+    fn into_tweet(self, user: User) -> Tweet {
+        let reply_to = self
+            .referenced_tweets
+            .iter()
+            .find(|referenced| referenced.kind == "replied_to")
+            .map(|referenced| referenced.id.clone());
+        let is_retweet = self
+            .referenced_tweets
+            .iter()
+            .any(|referenced| referenced.kind == "retweeted");
+
+        Tweet {
+            created_at: self.created_at,
+            id_str: self.id,
+            text: self.text,
+            user,
+            reply_to,
+            reply_to_user_id: self.in_reply_to_user_id,
+            lang: self.lang,
+            entities: Entity {
+                hashtags: self.entities.hashtags.into_iter().map(|h| h.tag).collect(),
+                user_mentions: self
+                    .entities
+                    .mentions
+                    .into_iter()
+                    .filter_map(|m| m.id)
+                    .collect(),
+                symbols: self.entities.cashtags.into_iter().map(|c| c.tag).collect(),
+                urls: self.entities.urls,
+            },
+            is_retweet,
+            coordinates: None,
+            edit_history_tweet_ids: self.edit_history_tweet_ids,
+            retweet_count: self.public_metrics.as_ref().map(|m| m.retweet_count),
+            favorite_count: self.public_metrics.as_ref().map(|m| m.like_count),
+            scopes: None,
+            raw_json: None,
+            #[cfg(feature = "extra_fields")]
+            extra: HashMap::new(),
+        }
+    }
+}
+
+// Options shared by every file parsed in a run
+#[derive(Debug, Default, Clone)]
+pub struct ParseOptions {
+    pub use_mmap: bool,
+    // When set, only tweets with one of these ids are kept
+    pub id_filter: Option<HashSet<String>>,
+    // When set, only tweets created after this date are kept
+    pub since: Option<DateTime<Utc>>,
+    // Keep the original line of v1.1 tweets in `Tweet::raw_json`
+    pub keep_raw_json: bool,
+    // Decode invalid UTF-8 as U+FFFD instead of counting the line as a parse error
+    pub replace_invalid_utf8: bool,
+    pub on_error: ParseErrorStrategy,
+}
+
+// What happens to a line that fails to parse
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ParseErrorStrategy {
+    /// Count the line as a parse error and go on
+    #[default]
+    Skip,
+    /// Try to repair common corruptions such as trailing commas or truncated
+    /// objects, and skip the line if that fails
+    Retry,
+    /// Stop the pipeline at the first parse error
+    Abort,
+}
+
+impl ParseOptions {
+    fn wants(&self, id: &str, created_at: DateTime<Utc>) -> bool {
+        self.id_filter.as_ref().is_none_or(|ids| ids.contains(id))
+            && self.since.is_none_or(|since| created_at > since)
+    }
+
+    // Repaired line to parse again when `--on-error retry` is set
+    fn repair(&self, line: &str, stats: &mut ParseStats) -> Option<String> {
+        if self.on_error != ParseErrorStrategy::Retry {
+            return None;
+        }
+        stats.repair_attempts += 1;
+        let repaired = try_repair_json(line)?;
+        stats.repaired_lines += 1;
+        Some(repaired)
+    }
+
+    // Error that stops the file when `--on-error abort` is set
+    fn after_error(&self, source: &str, line: u64) -> Option<crate::error::PipelineError> {
+        (self.on_error == ParseErrorStrategy::Abort).then(|| {
+            crate::error::PipelineError::ParseAborted(format!("{} at line {}", source, line))
+        })
+    }
+}
+
+// Best-effort repair of a corrupted JSON line: drops trailing commas and
+// anything after the top-level value, and closes a truncated string, array or
+// object. Returns the repaired line only if it is valid JSON
+fn try_repair_json(line: &str) -> Option<String> {
+    fn drop_trailing_comma(out: &mut String) {
+        out.truncate(out.trim_end().len());
+        if out.ends_with(',') {
+            out.pop();
+        }
+    }
+
+    let mut out = String::with_capacity(line.len() + 8);
+    let mut closers = Vec::new();
+    let mut in_string = false;
+    let mut escaped = false;
+    for c in line.trim().chars() {
+        if in_string {
+            out.push(c);
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
+            }
+            continue;
+        }
+        match c {
+            '"' => {
+                in_string = true;
+                out.push(c);
+            }
+            '{' => {
+                closers.push('}');
+                out.push(c);
+            }
+            '[' => {
+                closers.push(']');
+                out.push(c);
+            }
+            '}' | ']' => {
+                drop_trailing_comma(&mut out);
+                if closers.pop() != Some(c) {
+                    return None;
+                }
+                out.push(c);
+                if closers.is_empty() {
+                    break;
+                }
+            }
+            _ => out.push(c),
+        }
+    }
+
+    // Truncated line
+    if in_string {
+        if escaped {
+            out.pop();
+        }
+        out.push('"');
+    }
+    if out.trim_end().ends_with(':') {
+        out.push_str("null");
+    }
+    while let Some(closer) = closers.pop() {
+        drop_trailing_comma(&mut out);
+        out.push(closer);
+    }
+
+    serde_json::from_str::<serde_json::Value>(&out)
+        .ok()
+        .map(|_| out)
+}
+
+// One line of a v1.1 streaming archive
+#[derive(Debug)]
+pub enum RawEvent {
+    TweetEvent(Box<Tweet>),
+    Delete,
+    Limit { track: u64 },
+    ScrubGeo,
+    StatusWithheld,
+    UserWithheld,
+    Disconnect { reason: String },
+}
+
+impl RawEvent {
+    fn from_value(value: serde_json::Value) -> Result<Self, serde_json::Error> {
+        if value.get("delete").is_some() {
+            return Ok(RawEvent::Delete);
+        }
+        if let Some(limit) = value.get("limit") {
+            let track = limit.get("track").and_then(|t| t.as_u64()).unwrap_or(0);
+            return Ok(RawEvent::Limit { track });
+        }
+        if value.get("scrub_geo").is_some() {
+            return Ok(RawEvent::ScrubGeo);
+        }
+        if value.get("status_withheld").is_some() {
+            return Ok(RawEvent::StatusWithheld);
+        }
+        if value.get("user_withheld").is_some() {
+            return Ok(RawEvent::UserWithheld);
+        }
+        if let Some(disconnect) = value.get("disconnect") {
+            let reason = disconnect
+                .get("reason")
+                .and_then(|r| r.as_str())
+                .unwrap_or_default()
+                .to_string();
+            return Ok(RawEvent::Disconnect { reason });
+        }
+
+        let mut tweet = Tweet::deserialize(&value)?;
+        tweet.is_retweet = value.get("retweeted_status").is_some();
+        tweet.text = extract_text(&value);
+        Ok(RawEvent::TweetEvent(Box::new(tweet)))
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ParseStats {
+    pub tweet_num: u64,
+    pub deleted: u32,
+    pub retweet_num: u32,
+    pub duplicates_removed: u32,
+    pub parse_errors: u32,
+    // Lines `--on-error retry` tried to repair, and the ones it could
+    pub repair_attempts: u32,
+    pub repaired_lines: u32,
+    // Tweets the stream dropped because of rate limiting, as reported by limit notices
+    pub limited_tweets: u64,
+    pub scrub_geo_events: u32,
+    pub withheld_events: u32,
+    pub disconnect_events: u32,
+}
+
+impl ParseStats {
+    fn count_event(&mut self, event: &RawEvent) {
+        match event {
+            RawEvent::TweetEvent(_) => (),
+            RawEvent::Delete => self.deleted += 1,
+            RawEvent::Limit { track } => self.limited_tweets += track,
+            RawEvent::ScrubGeo => self.scrub_geo_events += 1,
+            RawEvent::StatusWithheld | RawEvent::UserWithheld => self.withheld_events += 1,
+            RawEvent::Disconnect { reason } => {
+                eprintln!("Stream disconnect recorded in the archive: {}", reason);
+                self.disconnect_events += 1;
+            }
+        }
+    }
+}
+
+impl AddAssign for ParseStats {
+    fn add_assign(&mut self, other: Self) {
+        self.tweet_num += other.tweet_num;
+        self.deleted += other.deleted;
+        self.retweet_num += other.retweet_num;
+        self.duplicates_removed += other.duplicates_removed;
+        self.parse_errors += other.parse_errors;
+        self.repair_attempts += other.repair_attempts;
+        self.repaired_lines += other.repaired_lines;
+        self.limited_tweets += other.limited_tweets;
+        self.scrub_geo_events += other.scrub_geo_events;
+        self.withheld_events += other.withheld_events;
+        self.disconnect_events += other.disconnect_events;
+    }
+}
+
+// Tweets and statistics of one input file
+#[derive(Debug, Default)]
+pub struct ParseResult {
+    pub tweets: Vec<Tweet>,
+    pub stats: ParseStats,
+    pub file_path: PathBuf,
+    pub parse_duration: Duration,
+}
+
+impl ParseResult {
+    fn finished(
+        file_path: &str,
+        started: Instant,
+        (tweets, stats): (Vec<Tweet>, ParseStats),
+    ) -> Self {
+        Self {
+            tweets,
+            stats,
+            file_path: PathBuf::from(file_path),
+            parse_duration: started.elapsed(),
+        }
+    }
+
+    pub fn secs_per_tweet(&self) -> f64 {
+        self.parse_duration.as_secs_f64() / self.tweets.len().max(1) as f64
+    }
+}
+
+pub fn parse_file(
+    filename: String,
+    options: &ParseOptions,
+) -> Result<ParseResult, crate::error::PipelineError> {
     println!("Parsing file {}", filename);
+    let started = Instant::now();
 
     let file = File::open(filename.clone()).unwrap();
 
-    let mut tweets = vec![];
-    let mut deleted = 0;
-    let mut tweet_num: u64 = 0;
-    let mut retweet_num = 0;
-
-    lines::read_lines!(line in LineReader::new(file), {
-        tweet_num += 1;
-        let content = from_utf8(line.unwrap()).unwrap();
-        if content.contains("\"delete\":") {
-            deleted += 1;
+    if !options.use_mmap {
+        let parsed = parse_reader(BufReader::new(file), &filename, options)?;
+        return Ok(ParseResult::finished(&filename, started, parsed));
+    }
+
+    let mut parser = LineParser::new(&filename, options);
+    // Safety: the mapping is only valid as long as no other process truncates
+    // or rewrites the file, which is why this path is opt-in
+    let mmap = unsafe { Mmap::map(&file) }.unwrap();
+    for line in mmap.split(|byte| *byte == b'\n') {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.is_empty() {
+            continue;
+        }
+        parser.handle_bytes(line);
+        if parser.aborted.is_some() {
+            break;
+        }
+    }
+    Ok(ParseResult::finished(&filename, started, parser.finish()?))
+}
+
+// Reads a JSON lines file of user objects, as found in user metadata dumps.
+// Returns the users and the number of lines that failed to parse
+pub fn parse_users_file(path: &Path) -> io::Result<(Vec<User>, u32)> {
+    let mut users = Vec::new();
+    let mut errors = 0;
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        let line = line.trim_start_matches('\u{feff}').trim();
+        if line.is_empty() {
             continue;
         }
+        match serde_json::from_str::<User>(line) {
+            Ok(user) => users.push(user),
+            Err(_) => errors += 1,
+        }
+    }
+    Ok((users, errors))
+}
+
+// Reads a Parquet file whose columns are named like the v1.1 JSON fields, with
+// `user` and `entities` as nested groups and `created_at` in the Twitter format.
+// Every row goes through the same path as a JSON line
+#[cfg(feature = "parquet")]
+pub fn parse_parquet_file(
+    filename: String,
+    options: &ParseOptions,
+) -> Result<ParseResult, crate::error::PipelineError> {
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+
+    println!("Parsing file {}", filename);
+    let started = Instant::now();
+
+    let reader = SerializedFileReader::new(File::open(&filename).map_err(|e| {
+        crate::error::PipelineError::UnexpectedResult(format!("{}: {}", filename, e))
+    })?)?;
+    let mut parser = LineParser::new(&filename, options);
+    for row in reader.get_row_iter(None)? {
+        match row {
+            Ok(row) => parser.handle_line(&row.to_json_value().to_string()),
+            Err(e) => parser.read_error(io::Error::other(e)),
+        }
+        if parser.aborted.is_some() {
+            break;
+        }
+    }
+    Ok(ParseResult::finished(&filename, started, parser.finish()?))
+}
+
+// Parses v1.1 tweets from any line-oriented source, `source` is only used in messages
+pub fn parse_reader(
+    reader: impl BufRead,
+    source: &str,
+    options: &ParseOptions,
+) -> Result<(Vec<Tweet>, ParseStats), crate::error::PipelineError> {
+    let mut parser = LineParser::new(source, options);
+    for line in reader.split(b'\n') {
+        match line {
+            Ok(line) => parser.handle_bytes(&line),
+            Err(e) => parser.read_error(e),
+        }
+        if parser.aborted.is_some() {
+            break;
+        }
+    }
+    parser.finish()
+}
+
+// Per-file state while parsing v1.1 tweets line by line
+struct LineParser<'a> {
+    source: &'a str,
+    options: &'a ParseOptions,
+    tweets: Vec<Tweet>,
+    stats: ParseStats,
+    // Line number of the first occurrence of every tweet id in this file
+    seen_ids: HashMap<String, u64>,
+    // Set by the first error under `--on-error abort`, the file is not read further
+    aborted: Option<crate::error::PipelineError>,
+}
+
+impl<'a> LineParser<'a> {
+    fn new(source: &'a str, options: &'a ParseOptions) -> Self {
+        Self {
+            source,
+            options,
+            tweets: vec![],
+            stats: ParseStats::default(),
+            seen_ids: HashMap::new(),
+            aborted: None,
+        }
+    }
+
+    fn handle_bytes(&mut self, line: &[u8]) {
+        match from_utf8(line) {
+            Ok(line) => self.handle_line(line),
+            Err(_) if self.options.replace_invalid_utf8 => {
+                self.handle_line(&String::from_utf8_lossy(line))
+            }
+            Err(e) => self.read_error(io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
+    }
+
+    fn handle_line(&mut self, content: &str) {
+        // Dumps generated on Windows end lines with \r\n, which would otherwise
+        // end up in the last string value of the line, and may start with a BOM
+        let content = content
+            .trim_end_matches(['\n', '\r'])
+            .trim_start_matches('\u{feff}');
+        // Blank lines, such as a trailing \r\n, are not tweets
+        if content.trim().is_empty() {
+            return;
+        }
+        self.stats.tweet_num += 1;
+
+        let parse = |line: &str| {
+            serde_json::from_str::<serde_json::Value>(line).and_then(RawEvent::from_value)
+        };
+        let mut event = parse(content);
+        if event.is_err()
+            && let Some(repaired) = self.options.repair(content, &mut self.stats)
+        {
+            event = parse(&repaired);
+        }
+        let mut tweet = match event {
+            Ok(RawEvent::TweetEvent(tweet)) => *tweet,
+            Ok(event) => {
+                self.stats.count_event(&event);
+                return;
+            }
+            Err(e) => {
+                self.stats.parse_errors += 1;
+                eprintln!(
+                    "Failed to parse file {} \nline: {}\n {}",
+                    self.source, e, content
+                );
+                self.aborted = self.options.after_error(self.source, self.stats.tweet_num);
+                return;
+            }
+        };
+
+        if !self.options.wants(&tweet.id_str, tweet.created_at) {
+            return;
+        }
+        if let Some(first_line) = self.seen_ids.get(&tweet.id_str) {
+            eprintln!(
+                "Duplicate tweet {} in file {} (lines {} and {}), keeping the first",
+                tweet.id_str, self.source, first_line, self.stats.tweet_num
+            );
+            self.stats.duplicates_removed += 1;
+            return;
+        }
+        self.seen_ids
+            .insert(tweet.id_str.clone(), self.stats.tweet_num);
+
+        if tweet.is_retweet {
+            self.stats.retweet_num += 1;
+        }
+        if self.options.keep_raw_json {
+            tweet.raw_json = Some(content.to_owned());
+        }
+        self.tweets.push(tweet);
+    }
+
+    // A line that could not be read at all, e.g. because it is not valid UTF-8
+    fn read_error(&mut self, error: io::Error) {
+        self.stats.tweet_num += 1;
+        self.stats.parse_errors += 1;
+        eprintln!(
+            "Failed to read line {} of file {}: {}",
+            self.stats.tweet_num, self.source, error
+        );
+        self.aborted = self.options.after_error(self.source, self.stats.tweet_num);
+    }
+
+    fn finish(self) -> Result<(Vec<Tweet>, ParseStats), crate::error::PipelineError> {
+        match self.aborted {
+            Some(error) => Err(error),
+            None => Ok((self.tweets, self.stats)),
+        }
+    }
+}
+
+// First invalid UTF-8 sequence of a file
+#[derive(Debug)]
+pub struct InvalidUtf8 {
+    pub offset: u64,
+    // Up to 20 bytes on each side, decoded lossily
+    pub context: String,
+}
+
+pub fn find_invalid_utf8(path: &Path) -> io::Result<Option<InvalidUtf8>> {
+    const CONTEXT_BYTES: usize = 20;
+
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut line = Vec::new();
+    let mut offset = 0u64;
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            return Ok(None);
+        }
+        if let Err(e) = from_utf8(&line) {
+            let position = e.valid_up_to();
+            let start = position.saturating_sub(CONTEXT_BYTES);
+            let end = (position + CONTEXT_BYTES).min(line.len());
+            return Ok(Some(InvalidUtf8 {
+                offset: offset + position as u64,
+                context: String::from_utf8_lossy(&line[start..end]).into_owned(),
+            }));
+        }
+        offset += line.len() as u64;
+    }
+}
 
-        match serde_json::from_str::<Tweet>(&content) {
-            Ok(mut tweet) => {
-                if content.contains("\"retweeted_status\":") {
-                    retweet_num += 1;
-                    tweet.is_retweet = true;
+pub fn detect_format(line: &str) -> FormatVersion {
+    match serde_json::from_str::<serde_json::Value>(line) {
+        Ok(value) if value.get("data").is_some() => FormatVersion::V2,
+        _ => FormatVersion::V1,
+    }
+}
+
+// Resolves `FormatVersion::Auto` by sniffing the first line of the file
+pub fn detect_file_format(filename: &str) -> io::Result<FormatVersion> {
+    let mut first_line = Vec::new();
+    BufReader::new(File::open(filename)?).read_until(b'\n', &mut first_line)?;
+    // Invalid UTF-8 is reported by the parser, with the line it is on
+    Ok(detect_format(&String::from_utf8_lossy(&first_line)))
+}
+
+pub fn parse_file_v2(
+    filename: String,
+    options: &ParseOptions,
+) -> Result<ParseResult, crate::error::PipelineError> {
+    println!("Parsing file {} (Twitter API v2)", filename);
+    let started = Instant::now();
+
+    let file = File::open(&filename)?;
+
+    let mut tweets = vec![];
+    let mut stats = ParseStats::default();
+    let mut seen_ids: HashMap<String, u64> = HashMap::new();
+
+    for (line_num, line) in BufReader::new(file).lines().enumerate() {
+        let line_num = line_num as u64 + 1;
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                stats.parse_errors += 1;
+                eprintln!("Failed to read a line of file {}: {}", filename, e);
+                if let Some(error) = options.after_error(&filename, line_num) {
+                    return Err(error);
                 }
-                let json_value: serde_json::Value = serde_json::from_str(content).unwrap();
-                tweet.text = extract_text(&json_value);
-                tweets.push(tweet);
+                continue;
             }
+        };
+        let content = line.trim_end_matches('\r');
+        if content.trim().is_empty() {
+            continue;
+        }
+        let mut response = serde_json::from_str::<ResponseV2>(content);
+        if response.is_err()
+            && let Some(repaired) = options.repair(content, &mut stats)
+        {
+            response = serde_json::from_str::<ResponseV2>(&repaired);
+        }
+        let response = match response {
+            Ok(response) => response,
             Err(e) => {
-                eprintln!("Failed to parse file {} \nline: {}\n {}", filename, e, content);
+                stats.parse_errors += 1;
+                eprintln!(
+                    "Failed to parse file {} \nline: {}\n {}",
+                    filename, e, content
+                );
+                if let Some(error) = options.after_error(&filename, line_num) {
+                    return Err(error);
+                }
+                continue;
+            }
+        };
+
+        let users: HashMap<String, User> = response
+            .includes
+            .users
+            .into_iter()
+            .map(|user| (user.id.clone(), user.into()))
+            .collect();
+
+        for tweet in response.data {
+            stats.tweet_num += 1;
+            let Some(user) = users.get(&tweet.author_id) else {
+                eprintln!(
+                    "Tweet {} in file {} has no matching author in includes.users",
+                    tweet.id, filename
+                );
+                continue;
+            };
+            if !options.wants(&tweet.id, tweet.created_at) {
+                continue;
+            }
+            if let Some(first) = seen_ids.get(&tweet.id) {
+                eprintln!(
+                    "Duplicate tweet {} in file {} (tweets {} and {}), keeping the first",
+                    tweet.id, filename, first, stats.tweet_num
+                );
+                stats.duplicates_removed += 1;
+                continue;
+            }
+            seen_ids.insert(tweet.id.clone(), stats.tweet_num);
+
+            let tweet = tweet.into_tweet(user.clone());
+            if tweet.is_retweet {
+                stats.retweet_num += 1;
             }
+            tweets.push(tweet);
         }
-    });
-    return (tweets, deleted, tweet_num, retweet_num);
+    }
+    Ok(ParseResult::finished(&filename, started, (tweets, stats)))
+}
+
+fn deserialize_one_or_many<'de, D>(deserializer: D) -> Result<Vec<TweetV2>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    // The filtered stream sends a single object, search endpoints send an array
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(Box<TweetV2>),
+        Many(Vec<TweetV2>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(tweet) => vec![*tweet],
+        OneOrMany::Many(tweets) => tweets,
+    })
 }
 
 fn deserialize_twitter_date<'de, D>(deserializer: D) -> Result<DateTime<Utc>, D::Error>
@@ -97,9 +962,11 @@ fn deserialize_user_mentions<'de, D>(deserializer: D) -> Result<Vec<String>, D::
 where
     D: serde::Deserializer<'de>,
 {
-    let mentions: Vec<serde_json::Value> = Deserialize::deserialize(deserializer)?;
+    // A null array is read as an empty one, as is a null element
+    let mentions: Option<Vec<serde_json::Value>> = Deserialize::deserialize(deserializer)?;
     let ids = mentions
         .into_iter()
+        .flatten()
         .filter_map(|mention| {
             mention
                 .get("id_str")
@@ -113,9 +980,10 @@ fn deserialize_hashtags<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error
 where
     D: serde::Deserializer<'de>,
 {
-    let hashtag_maps: Vec<serde_json::Value> = Deserialize::deserialize(deserializer)?;
+    let hashtag_maps: Option<Vec<serde_json::Value>> = Deserialize::deserialize(deserializer)?;
     let hashtags = hashtag_maps
         .into_iter()
+        .flatten()
         .filter_map(|mention| {
             mention
                 .get("text")
@@ -125,16 +993,303 @@ where
     Ok(hashtags)
 }
 
+fn deserialize_symbols<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let symbol_maps: Option<Vec<serde_json::Value>> = Deserialize::deserialize(deserializer)?;
+    let symbols = symbol_maps
+        .into_iter()
+        .flatten()
+        .filter_map(|symbol| {
+            symbol
+                .get("text")
+                .and_then(|v| v.as_str().map(|s| s.to_string()))
+        })
+        .collect();
+    Ok(symbols)
+}
+
+fn deserialize_coordinates<'de, D>(deserializer: D) -> Result<Option<[f64; 2]>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    // GeoJSON point: {"type": "Point", "coordinates": [longitude, latitude]}
+    let point: Option<serde_json::Value> = Deserialize::deserialize(deserializer)?;
+    let coordinates = point
+        .as_ref()
+        .and_then(|p| p.get("coordinates"))
+        .and_then(|c| c.as_array())
+        .and_then(|c| match c.as_slice() {
+            [lon, lat] => Some([lon.as_f64()?, lat.as_f64()?]),
+            _ => None,
+        });
+    Ok(coordinates)
+}
+
 fn extract_text(json: &serde_json::Value) -> String {
     if let Some(retweet) = json.get("retweeted_status") {
         if let Some(extended_tweet) = retweet.get("extended_tweet") {
             return extended_tweet.get("full_text").unwrap().to_string();
         }
-        return retweet.get("text").unwrap().to_string();
+        retweet.get("text").unwrap().to_string()
     } else {
         if let Some(extended_tweet) = json.get("extended_tweet") {
             return extended_tweet.get("full_text").unwrap().to_string();
         }
-        return json.get("text").unwrap().to_string();
+        json.get("text").unwrap().to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str) -> String {
+        format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    // Parses a fixture that must hold exactly one valid tweet
+    fn parse_single_tweet(name: &str) -> Tweet {
+        let mut parsed = parse_file(fixture(name), &ParseOptions::default()).unwrap();
+        assert_eq!(parsed.stats.parse_errors, 0);
+        assert_eq!(parsed.tweets.len(), 1);
+        parsed.tweets.remove(0)
+    }
+
+    #[test]
+    fn tweet_without_optional_fields_parses() {
+        let tweet = parse_single_tweet("missing_fields/tweet_optional_fields_absent.jsonl");
+        assert_eq!(tweet.id_str, "1131580395845767168");
+        assert_eq!(tweet.reply_to, None);
+        assert_eq!(tweet.reply_to_user_id, None);
+        assert_eq!(tweet.lang, None);
+        assert!(tweet.entities.hashtags.is_empty());
+        assert!(!tweet.is_retweet);
+        assert_eq!(tweet.coordinates, None);
+        assert_eq!(tweet.edit_history_tweet_ids, None);
+        assert_eq!(tweet.retweet_count, None);
+        assert_eq!(tweet.favorite_count, None);
+        assert!(tweet.scopes.is_none());
+    }
+
+    #[test]
+    fn user_without_optional_fields_parses() {
+        let user = parse_single_tweet("missing_fields/user_optional_fields_absent.jsonl").user;
+        assert_eq!(user.screen_name, "traveller");
+        assert_eq!(user.location, None);
+        assert!(!user.verified);
+        assert_eq!(user.listed_count, None);
+        assert_eq!(user.utc_offset, None);
+        assert_eq!(user.protected, None);
+    }
+
+    #[test]
+    fn null_optional_fields_parse_as_none() {
+        let tweet = parse_single_tweet("missing_fields/optional_fields_null.jsonl");
+        assert_eq!(tweet.reply_to, None);
+        assert_eq!(tweet.lang, None);
+        assert_eq!(tweet.coordinates, None);
+        assert_eq!(tweet.retweet_count, None);
+        assert_eq!(tweet.user.location, None);
+        assert_eq!(tweet.user.listed_count, None);
+        assert_eq!(tweet.user.protected, None);
+    }
+
+    #[test]
+    fn missing_or_empty_entities_default_to_empty() {
+        let parsed =
+            parse_file(fixture("missing_entities.jsonl"), &ParseOptions::default()).unwrap();
+        assert_eq!(parsed.stats.parse_errors, 0);
+        assert_eq!(parsed.tweets.len(), 2);
+        for tweet in &parsed.tweets {
+            assert!(tweet.entities.hashtags.is_empty());
+            assert!(tweet.entities.user_mentions.is_empty());
+            assert!(tweet.entities.symbols.is_empty());
+            assert!(tweet.entities.urls.is_empty());
+        }
+    }
+
+    #[test]
+    fn null_entity_arrays_and_elements_are_skipped() {
+        let parsed = parse_file(fixture("null_entities.jsonl"), &ParseOptions::default()).unwrap();
+        assert_eq!(parsed.stats.parse_errors, 0);
+        assert_eq!(parsed.tweets.len(), 2);
+        let entities = &parsed.tweets[0].entities;
+        assert!(entities.hashtags.is_empty());
+        assert!(entities.user_mentions.is_empty());
+        assert!(entities.symbols.is_empty());
+        let entities = &parsed.tweets[1].entities;
+        assert_eq!(entities.hashtags, ["delay"]);
+        assert_eq!(entities.user_mentions, ["56377143"]);
+        assert!(entities.symbols.is_empty());
+    }
+
+    #[cfg(feature = "parquet")]
+    #[test]
+    fn parquet_rows_are_parsed_like_json_lines() {
+        let parsed =
+            parse_parquet_file(fixture("tweets.parquet"), &ParseOptions::default()).unwrap();
+        assert_eq!(parsed.stats.parse_errors, 0);
+        assert_eq!(parsed.tweets.len(), 2);
+
+        let reply = &parsed.tweets[1];
+        assert_eq!(reply.id_str, "1131581971696091136");
+        assert!(reply.text.contains("@user Sorry to hear that"));
+        assert_eq!(reply.reply_to.as_deref(), Some("1131580395845767168"));
+        assert_eq!(reply.lang.as_deref(), Some("en"));
+        assert_eq!(reply.user.screen_name, "KLM");
+        assert_eq!(reply.user.followers_count, 3);
+        assert_eq!(parsed.tweets[0].reply_to, None);
+    }
+
+    fn assert_crlf_fixture_parsed(parsed: ParseResult) {
+        assert_eq!(parsed.stats.parse_errors, 0);
+        assert_eq!(parsed.tweets.len(), 2);
+        let [tweet, reply] = &parsed.tweets[..] else {
+            unreachable!()
+        };
+        assert_eq!(tweet.lang.as_deref(), Some("en"));
+        assert_eq!(tweet.entities.hashtags, ["KLM"]);
+        assert_eq!(tweet.user.location.as_deref(), Some("Amsterdam"));
+        assert!(!tweet.text.contains('\r'));
+        assert_eq!(reply.reply_to.as_deref(), Some("1131580395845767168"));
+        assert_eq!(reply.entities.user_mentions, ["1001"]);
+        assert_eq!(
+            reply.user.created_at.to_rfc3339(),
+            "2009-03-10T08:00:00+00:00"
+        );
+    }
+
+    #[test]
+    fn crlf_line_endings_are_stripped() {
+        let parsed = parse_file(fixture("crlf.jsonl"), &ParseOptions::default()).unwrap();
+        assert_crlf_fixture_parsed(parsed);
+    }
+
+    #[test]
+    fn crlf_line_endings_are_stripped_with_mmap() {
+        let options = ParseOptions {
+            use_mmap: true,
+            ..Default::default()
+        };
+        assert_crlf_fixture_parsed(parse_file(fixture("crlf.jsonl"), &options).unwrap());
+    }
+
+    #[test]
+    fn missing_v2_file_is_an_io_error() {
+        let result = parse_file_v2(fixture("missing_v2.jsonl"), &ParseOptions::default());
+        assert!(matches!(result, Err(crate::error::PipelineError::Io(_))));
+    }
+
+    mod properties {
+        use super::super::*;
+        use chrono::FixedOffset;
+        use proptest::prelude::*;
+        use serde_json::{Value, json};
+
+        // Any JSON value, nested a few levels deep
+        fn any_json() -> impl Strategy<Value = Value> {
+            let leaf = prop_oneof![
+                Just(Value::Null),
+                any::<bool>().prop_map(Value::from),
+                any::<i64>().prop_map(Value::from),
+                any::<f64>().prop_map(Value::from),
+                ".*".prop_map(Value::from),
+            ];
+            leaf.prop_recursive(3, 32, 4, |inner| {
+                prop_oneof![
+                    prop::collection::vec(inner.clone(), 0..4).prop_map(Value::from),
+                    prop::collection::hash_map("[a-z_]{1,8}", inner, 0..4)
+                        .prop_map(|map| map.into_iter().collect::<serde_json::Map<_, _>>().into()),
+                ]
+            })
+        }
+
+        // Entity objects, some with the field the deserializer reads, mixed
+        // with nulls and arbitrary values
+        fn entities(field: &'static str) -> impl Strategy<Value = Vec<Value>> {
+            let entity = prop_oneof![
+                ".*".prop_map(move |text| json!({ field: text, "indices": [0, 1] })),
+                any_json().prop_map(move |value| json!({ field: value })),
+                any_json(),
+            ];
+            prop::collection::vec(entity, 0..8)
+        }
+
+        // What the entity deserializers read from each element
+        fn strings_of(elements: &[Value], field: &str) -> Vec<String> {
+            elements
+                .iter()
+                .filter_map(|element| element.get(field)?.as_str().map(str::to_owned))
+                .collect()
+        }
+
+        proptest! {
+            #[test]
+            fn twitter_dates_round_trip(secs in 0i64..4_102_444_800) {
+                let date = DateTime::from_timestamp(secs, 0).unwrap();
+                let text = date.format("%a %b %d %H:%M:%S +0000 %Y").to_string();
+                prop_assert_eq!(deserialize_twitter_date(&Value::String(text)).unwrap(), date);
+            }
+
+            #[test]
+            fn dates_with_an_offset_are_converted_to_utc(
+                secs in 0i64..4_102_444_800,
+                offset_minutes in -14 * 60..=14 * 60,
+            ) {
+                let date = DateTime::from_timestamp(secs, 0).unwrap();
+                let offset = FixedOffset::east_opt(offset_minutes * 60).unwrap();
+                let text = date
+                    .with_timezone(&offset)
+                    .format("%a %b %d %H:%M:%S %z %Y")
+                    .to_string();
+                prop_assert_eq!(deserialize_twitter_date(&Value::String(text)).unwrap(), date);
+            }
+
+            #[test]
+            fn dates_parsed_from_any_text_round_trip(text in ".*") {
+                if let Ok(date) = deserialize_twitter_date(&Value::String(text)) {
+                    let text = date.format("%a %b %d %H:%M:%S +0000 %Y").to_string();
+                    prop_assert_eq!(deserialize_twitter_date(&Value::String(text)).unwrap(), date);
+                }
+            }
+
+            #[test]
+            fn non_string_dates_are_errors(value in any_json()) {
+                prop_assume!(!value.is_string());
+                prop_assert!(deserialize_twitter_date(&value).is_err());
+            }
+
+            #[test]
+            fn hashtags_are_the_text_of_each_element(elements in entities("text")) {
+                let expected = strings_of(&elements, "text");
+                prop_assert_eq!(deserialize_hashtags(&Value::from(elements)).unwrap(), expected);
+            }
+
+            #[test]
+            fn user_mentions_are_the_id_of_each_element(elements in entities("id_str")) {
+                let expected = strings_of(&elements, "id_str");
+                prop_assert_eq!(
+                    deserialize_user_mentions(&Value::from(elements)).unwrap(),
+                    expected
+                );
+            }
+
+            #[test]
+            fn entities_other_than_arrays_or_null_are_errors(value in any_json()) {
+                prop_assume!(!value.is_array() && !value.is_null());
+                prop_assert!(deserialize_hashtags(&value).is_err());
+                prop_assert!(deserialize_user_mentions(&value).is_err());
+            }
+        }
+
+        #[test]
+        fn null_and_empty_entity_arrays_are_empty() {
+            for value in [Value::Null, json!([]), json!([null, null])] {
+                assert!(deserialize_hashtags(&value).unwrap().is_empty());
+                assert!(deserialize_user_mentions(&value).unwrap().is_empty());
+            }
+        }
     }
 }
@@ -1,10 +1,22 @@
-use tokio;
+use clap::Parser;
 mod app;
+mod archive;
+mod config;
 mod db;
+mod entity;
+mod error;
+mod filter;
 mod json;
+mod notify;
+mod progress;
+mod quality;
+mod sink;
+mod stage;
+mod stats;
 
 #[tokio::main]
 async fn main() {
-    let mut app = app::App::default();
+    let config = config::AppConfig::parse();
+    let mut app = app::App::new(config);
     app.run().await;
 }
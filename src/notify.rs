@@ -0,0 +1,122 @@
+use serde::Serialize;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::net::UnixListener;
+
+// How long to wait for the orchestrator to connect once the run is over
+const ACCEPT_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CompletionStatus {
+    Ok,
+    Error,
+}
+
+#[derive(Debug, Serialize)]
+struct CompletionEvent {
+    status: CompletionStatus,
+    tweets: usize,
+    elapsed_secs: f64,
+}
+
+// Listens on a Unix domain socket so that a sidecar can connect at any time
+// during the run and block until the completion event is written
+pub struct CompletionNotifier {
+    listener: UnixListener,
+    path: PathBuf,
+    started: Instant,
+}
+
+impl CompletionNotifier {
+    pub fn bind(path: &Path) -> io::Result<Self> {
+        // A socket left behind by a previous run would make the bind fail
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+        Ok(Self {
+            listener: UnixListener::bind(path)?,
+            path: path.to_owned(),
+            started: Instant::now(),
+        })
+    }
+
+    // Writes one JSON line to the first connected client and closes the socket
+    pub async fn send(self, status: CompletionStatus, tweets: usize) -> io::Result<()> {
+        let event = CompletionEvent {
+            status,
+            tweets,
+            elapsed_secs: self.started.elapsed().as_secs_f64(),
+        };
+        let mut line = serde_json::to_string(&event)?;
+        line.push('\n');
+
+        let result = match tokio::time::timeout(ACCEPT_TIMEOUT, self.listener.accept()).await {
+            Ok(Ok((mut stream, _))) => {
+                stream.write_all(line.as_bytes()).await?;
+                stream.shutdown().await
+            }
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "no client connected to the notify socket",
+            )),
+        };
+        drop(self.listener);
+        let _ = fs::remove_file(&self.path);
+        result
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct WebhookPayload<'a> {
+    status: CompletionStatus,
+    tweets: usize,
+    duration_secs: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+}
+
+// Posts the completion event to an HTTP endpoint, for monitoring systems that
+// cannot run a sidecar next to the pipeline
+pub struct WebhookNotifier {
+    url: String,
+    timeout: Duration,
+    started: Instant,
+}
+
+impl WebhookNotifier {
+    pub fn new(url: String, timeout: Duration) -> Self {
+        Self {
+            url,
+            timeout,
+            started: Instant::now(),
+        }
+    }
+
+    pub async fn send(
+        self,
+        status: CompletionStatus,
+        tweets: usize,
+        error: Option<&str>,
+    ) -> Result<(), reqwest::Error> {
+        let payload = WebhookPayload {
+            status,
+            tweets,
+            duration_secs: self.started.elapsed().as_secs_f64(),
+            error,
+        };
+        reqwest::Client::builder()
+            .timeout(self.timeout)
+            .build()?
+            .post(&self.url)
+            .json(&payload)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
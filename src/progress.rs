@@ -0,0 +1,96 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+
+// Intervals the ETA throughput is averaged over
+const ETA_INTERVALS: usize = 3;
+
+// Counters of the insert stage, updated after each file
+#[derive(Debug, Default)]
+pub struct Progress {
+    pub files_done: AtomicU64,
+    // Inserted or filtered out, the ETA is based on these
+    pub tweets_done: AtomicU64,
+    pub tweets_inserted: AtomicU64,
+}
+
+impl Progress {
+    pub fn file_done(&self, tweets: u64, inserted: u64) {
+        self.files_done.fetch_add(1, Ordering::Relaxed);
+        self.tweets_done.fetch_add(tweets, Ordering::Relaxed);
+        self.tweets_inserted.fetch_add(inserted, Ordering::Relaxed);
+    }
+}
+
+// Prints the progress of the import every interval from a background task,
+// for long imports where the per-batch messages scroll by too fast
+pub struct ProgressReporter {
+    progress: Arc<Progress>,
+    task: JoinHandle<()>,
+    total_files: u64,
+    started: Instant,
+}
+
+impl ProgressReporter {
+    pub fn spawn(
+        progress: Arc<Progress>,
+        interval: Duration,
+        total_files: u64,
+        total_tweets: u64,
+    ) -> Self {
+        let counters = progress.clone();
+        let task = tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            // The first tick completes immediately
+            ticker.tick().await;
+            let mut rates = VecDeque::with_capacity(ETA_INTERVALS);
+            let (mut last_done, mut last_inserted) = (0, 0);
+            loop {
+                ticker.tick().await;
+                let done = counters.tweets_done.load(Ordering::Relaxed);
+                let inserted = counters.tweets_inserted.load(Ordering::Relaxed);
+                if rates.len() == ETA_INTERVALS {
+                    rates.pop_front();
+                }
+                rates.push_back((done - last_done) as f64 / interval.as_secs_f64());
+                let rate = rates.iter().sum::<f64>() / rates.len() as f64;
+                let eta = if rate > 0. {
+                    let minutes = total_tweets.saturating_sub(done) as f64 / rate / 60.;
+                    format!("{:.0} minutes", minutes.ceil())
+                } else {
+                    "unknown".to_owned()
+                };
+                println!(
+                    "Progress: {}/{} files, {} tweets inserted, {:.0} tweets/sec (last interval), ETA: {}",
+                    counters.files_done.load(Ordering::Relaxed),
+                    total_files,
+                    inserted,
+                    (inserted - last_inserted) as f64 / interval.as_secs_f64(),
+                    eta
+                );
+                (last_done, last_inserted) = (done, inserted);
+            }
+        });
+        Self {
+            progress,
+            task,
+            total_files,
+            started: Instant::now(),
+        }
+    }
+
+    // Stops the task and prints the overall throughput
+    pub fn finish(self) {
+        self.task.abort();
+        let inserted = self.progress.tweets_inserted.load(Ordering::Relaxed);
+        println!(
+            "Progress: {}/{} files, {} tweets inserted, {:.0} tweets/sec overall",
+            self.progress.files_done.load(Ordering::Relaxed),
+            self.total_files,
+            inserted,
+            inserted as f64 / self.started.elapsed().as_secs_f64()
+        );
+    }
+}
@@ -0,0 +1,54 @@
+use serde::Serialize;
+use std::fmt;
+
+use crate::json::Tweet;
+
+const BUCKETS: usize = 10;
+
+// Share of the optional fields that are populated, from 0.0 to 1.0. Corpus
+// dumps of truncated API responses miss most of them
+pub fn tweet_quality_score(tweet: &Tweet) -> f32 {
+    let entities = &tweet.entities;
+    let populated = [
+        !tweet.text.is_empty(),
+        tweet.lang.is_some(),
+        tweet.coordinates.is_some() || tweet.user.location.is_some(),
+        !entities.hashtags.is_empty()
+            || !entities.user_mentions.is_empty()
+            || !entities.symbols.is_empty(),
+        tweet.reply_to.is_some() || tweet.reply_to_user_id.is_some(),
+        tweet.user.listed_count.is_some(),
+        tweet.user.utc_offset.is_some(),
+        tweet.user.protected.is_some(),
+    ];
+    populated.iter().filter(|field| **field).count() as f32 / populated.len() as f32
+}
+
+// Number of tweets per tenth of the quality score, a score of 1.0 counts in
+// the last bucket
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct ScoreDistribution {
+    pub buckets: [u64; BUCKETS],
+}
+
+impl ScoreDistribution {
+    pub fn add(&mut self, score: f32) {
+        let bucket = ((score * BUCKETS as f32) as usize).min(BUCKETS - 1);
+        self.buckets[bucket] += 1;
+    }
+}
+
+impl fmt::Display for ScoreDistribution {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, count) in self.buckets.iter().enumerate() {
+            writeln!(
+                f,
+                "  {:.1}-{:.1}: {}",
+                i as f32 / BUCKETS as f32,
+                (i + 1) as f32 / BUCKETS as f32,
+                count
+            )?;
+        }
+        Ok(())
+    }
+}
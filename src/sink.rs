@@ -0,0 +1,119 @@
+use std::sync::Arc;
+#[cfg(test)]
+use std::sync::Mutex;
+
+use crate::db::{
+    self, Credentials, InsertOptions, InsertStats, RelationStats, UserMentionStrategy,
+};
+use crate::error::PipelineError;
+use crate::json::Tweet;
+
+// Where the parsed tweets are written, so that App can run against another
+// store than Neo4j
+pub trait DataSink: Send + Sync {
+    async fn insert_tweets(
+        &self,
+        tweets: Vec<Tweet>,
+        options: &InsertOptions,
+    ) -> Result<InsertStats, PipelineError>;
+
+    // Links the inserted tweets to each other and to their users
+    async fn build_relations(&self) -> Result<RelationStats, PipelineError>;
+}
+
+pub struct Neo4jSink {
+    credentials: Arc<Credentials>,
+    mention_strategy: UserMentionStrategy,
+}
+
+impl Neo4jSink {
+    pub fn new(credentials: Arc<Credentials>, mention_strategy: UserMentionStrategy) -> Self {
+        Self {
+            credentials,
+            mention_strategy,
+        }
+    }
+}
+
+impl DataSink for Neo4jSink {
+    async fn insert_tweets(
+        &self,
+        tweets: Vec<Tweet>,
+        options: &InsertOptions,
+    ) -> Result<InsertStats, PipelineError> {
+        db::insert_new_tweets(self.credentials.clone(), tweets, options).await
+    }
+
+    async fn build_relations(&self) -> Result<RelationStats, PipelineError> {
+        let creds = &self.credentials;
+
+        // A failed check runs the step anyway
+        let has_replies = db::has_replies(creds.clone()).await.unwrap_or(true);
+        let has_mentions = db::has_tweets_with_mentions(creds.clone())
+            .await
+            .unwrap_or(true);
+
+        let replies_added = if has_replies {
+            db::add_replies_to_relation(creds.clone()).await?
+        } else {
+            println!("No tweet is a reply, skipping REPLIES_TO relations");
+            0
+        };
+        let mentions_added = if self.mention_strategy == UserMentionStrategy::Array {
+            0
+        } else if has_mentions {
+            db::add_user_mention_relation(creds.clone()).await?
+        } else {
+            println!("No tweet mentions a user, skipping MENTIONS relations");
+            0
+        };
+        if self.mention_strategy == UserMentionStrategy::Nodes && has_mentions {
+            db::remove_user_mention_arrays(creds.clone()).await?;
+        }
+        let stats = RelationStats {
+            replies_added,
+            mentions_added,
+            edits_added: db::add_edit_history_relations(creds.clone()).await?,
+            direct_replies_added: db::add_direct_reply_relation(creds.clone()).await?,
+        };
+        db::add_airline_labels(creds.clone()).await?;
+
+        Ok(stats)
+    }
+}
+
+// Keeps the tweets in memory, so that App can be tested without Neo4j
+#[cfg(test)]
+#[derive(Default)]
+pub struct InMemorySink {
+    tweets: Mutex<Vec<Tweet>>,
+}
+
+#[cfg(test)]
+impl InMemorySink {
+    pub fn tweets(&self) -> Vec<Tweet> {
+        self.tweets.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+impl DataSink for InMemorySink {
+    async fn insert_tweets(
+        &self,
+        tweets: Vec<Tweet>,
+        _options: &InsertOptions,
+    ) -> Result<InsertStats, PipelineError> {
+        let stats = InsertStats {
+            batches_ok: 1,
+            tweets_attempted: tweets.len(),
+            tweets_inserted: tweets.len(),
+            ..Default::default()
+        };
+        self.tweets.lock().unwrap().extend(tweets);
+        Ok(stats)
+    }
+
+    async fn build_relations(&self) -> Result<RelationStats, PipelineError> {
+        Ok(RelationStats::default())
+    }
+}
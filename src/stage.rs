@@ -0,0 +1,275 @@
+use clap::ValueEnum;
+use futures::future::LocalBoxFuture;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fmt;
+use std::time::Instant;
+
+use crate::error::PipelineError;
+
+// Steps of an import, in the order App::run goes through them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum StageId {
+    PrepareDb,
+    Parse,
+    Insert,
+    Relations,
+    DetectCycles,
+    ConversationDepth,
+    DepthHistogram,
+    TemporalProperties,
+    HashtagTrends,
+    ConsistencyCheck,
+}
+
+impl StageId {
+    // The name given to `--skip-stage` and `--only-stage`
+    pub fn name(self) -> &'static str {
+        match self {
+            StageId::PrepareDb => "prepare-db",
+            StageId::Parse => "parse",
+            StageId::Insert => "insert",
+            StageId::Relations => "relations",
+            StageId::DetectCycles => "detect-cycles",
+            StageId::ConversationDepth => "conversation-depth",
+            StageId::DepthHistogram => "depth-histogram",
+            StageId::TemporalProperties => "temporal-properties",
+            StageId::HashtagTrends => "hashtag-trends",
+            StageId::ConsistencyCheck => "consistency-check",
+        }
+    }
+}
+
+impl fmt::Display for StageId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StageResult {
+    pub stage: StageId,
+    pub duration_secs: f64,
+    // False when the stage failed, whether or not that ended the run
+    pub ok: bool,
+    pub error: Option<String>,
+}
+
+// One step of an import, run by StageRunner on the context `C` that the
+// stages hand their state forward in
+pub trait Stage<C> {
+    fn id(&self) -> StageId;
+
+    fn name(&self) -> &'static str {
+        self.id().name()
+    }
+
+    // Whether the stage has what it needs from the stages before it
+    fn should_run(&self, _ctx: &C) -> bool {
+        true
+    }
+
+    // Whether a failure of the stage ends the run
+    fn required(&self) -> bool {
+        true
+    }
+
+    fn execute<'a>(&'a self, ctx: &'a mut C) -> LocalBoxFuture<'a, Result<(), PipelineError>>;
+}
+
+// Decides which stages run from `--skip-stage` and `--only-stage`, and times
+// the ones that do
+pub struct StageRunner {
+    skip: HashSet<StageId>,
+    only: HashSet<StageId>,
+    results: Vec<StageResult>,
+}
+
+impl StageRunner {
+    pub fn new(skip: &[StageId], only: &[StageId]) -> Self {
+        Self {
+            skip: skip.iter().copied().collect(),
+            only: only.iter().copied().collect(),
+            results: Vec::new(),
+        }
+    }
+
+    // Runs the selected stages in order. Stops at the first required stage
+    // that fails and returns its error, the failures of the other stages are
+    // only recorded in the results
+    pub async fn run<C>(
+        &mut self,
+        stages: &[Box<dyn Stage<C>>],
+        ctx: &mut C,
+    ) -> Result<(), PipelineError> {
+        for stage in stages {
+            if !stage.should_run(ctx) || !self.selected(stage.id()) {
+                continue;
+            }
+            println!("Stage {} started", stage.name());
+            let started = Instant::now();
+            let result = stage.execute(ctx).await;
+            let duration = started.elapsed();
+            println!("Stage {} finished in {:.1?}", stage.name(), duration);
+            self.results.push(StageResult {
+                stage: stage.id(),
+                duration_secs: duration.as_secs_f64(),
+                ok: result.is_ok(),
+                error: result.as_ref().err().map(ToString::to_string),
+            });
+            if let Err(e) = result
+                && stage.required()
+            {
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    fn selected(&self, stage: StageId) -> bool {
+        if self.skip.contains(&stage) || (!self.only.is_empty() && !self.only.contains(&stage)) {
+            println!("Skipping stage {}", stage);
+            return false;
+        }
+        true
+    }
+
+    pub fn results(&self) -> &[StageResult] {
+        &self.results
+    }
+
+    pub fn report(&self) {
+        println!("Stages:");
+        for result in &self.results {
+            println!(
+                "  {:<20} {:>8.1}s {}",
+                result.stage.name(),
+                result.duration_secs,
+                match &result.error {
+                    None => "ok".to_string(),
+                    Some(e) => format!("failed: {}", e),
+                }
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Records the stages that ran, in order
+    #[derive(Default)]
+    struct Log(Vec<StageId>);
+
+    struct TestStage {
+        id: StageId,
+        fails: bool,
+        required: bool,
+    }
+
+    impl TestStage {
+        fn ok(id: StageId) -> Box<dyn Stage<Log>> {
+            Box::new(TestStage {
+                id,
+                fails: false,
+                required: true,
+            })
+        }
+
+        fn failing(id: StageId, required: bool) -> Box<dyn Stage<Log>> {
+            Box::new(TestStage {
+                id,
+                fails: true,
+                required,
+            })
+        }
+    }
+
+    impl Stage<Log> for TestStage {
+        fn id(&self) -> StageId {
+            self.id
+        }
+
+        fn required(&self) -> bool {
+            self.required
+        }
+
+        fn execute<'a>(
+            &'a self,
+            ctx: &'a mut Log,
+        ) -> LocalBoxFuture<'a, Result<(), PipelineError>> {
+            Box::pin(async move {
+                ctx.0.push(self.id);
+                if self.fails {
+                    return Err(PipelineError::Aborted(format!("{} failed", self.id)));
+                }
+                Ok(())
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn skip_and_only_select_the_stages() {
+        let stages = [
+            TestStage::ok(StageId::Parse),
+            TestStage::ok(StageId::Insert),
+            TestStage::ok(StageId::Relations),
+        ];
+
+        let mut log = Log::default();
+        let mut runner = StageRunner::new(&[StageId::Insert], &[]);
+        runner.run(&stages, &mut log).await.unwrap();
+        assert_eq!(log.0, [StageId::Parse, StageId::Relations]);
+
+        let mut log = Log::default();
+        let mut runner = StageRunner::new(&[], &[StageId::Insert]);
+        runner.run(&stages, &mut log).await.unwrap();
+        assert_eq!(log.0, [StageId::Insert]);
+        assert_eq!(runner.results().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn optional_failures_are_recorded_and_the_run_goes_on() {
+        let stages = [
+            TestStage::failing(StageId::DetectCycles, false),
+            TestStage::ok(StageId::ConsistencyCheck),
+        ];
+        let mut log = Log::default();
+        let mut runner = StageRunner::new(&[], &[]);
+
+        runner.run(&stages, &mut log).await.unwrap();
+
+        assert_eq!(log.0, [StageId::DetectCycles, StageId::ConsistencyCheck]);
+        let results = runner.results();
+        assert!(!results[0].ok);
+        assert_eq!(results[0].error.as_deref(), Some("detect-cycles failed"));
+        assert!(results[1].ok);
+        assert_eq!(results[1].error, None);
+    }
+
+    #[tokio::test]
+    async fn a_required_failure_ends_the_run() {
+        let stages = [
+            TestStage::failing(StageId::Insert, true),
+            TestStage::ok(StageId::Relations),
+        ];
+        let mut log = Log::default();
+        let mut runner = StageRunner::new(&[], &[]);
+
+        let error = runner.run(&stages, &mut log).await.unwrap_err();
+
+        assert_eq!(error.to_string(), "insert failed");
+        assert_eq!(log.0, [StageId::Insert]);
+        assert_eq!(runner.results().len(), 1);
+        assert!(!runner.results()[0].ok);
+    }
+
+    #[test]
+    fn names_match_the_command_line_values() {
+        for stage in StageId::value_variants() {
+            assert_eq!(stage.to_possible_value().unwrap().get_name(), stage.name());
+        }
+    }
+}
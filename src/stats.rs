@@ -0,0 +1,148 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+use crate::db::GraphStats;
+use crate::json::Tweet;
+use crate::quality::ScoreDistribution;
+use crate::stage::StageResult;
+
+// Summary of a pipeline run, written as JSON for monitoring scripts
+#[derive(Debug, Serialize)]
+pub struct PipelineStats {
+    pub started_at: DateTime<Utc>,
+    pub completed_at: DateTime<Utc>,
+    pub files_processed: usize,
+    // Matched by the input pattern but not JSON
+    pub skipped_files: usize,
+    // Over --max-file-size
+    pub skipped_large_files: usize,
+    pub tweets_parsed: u64,
+    pub tweets_inserted: usize,
+    pub retweet_percentage: f32,
+    pub deleted_events: u32,
+    pub parse_errors: u32,
+    pub repair_attempts: u32,
+    pub repaired_lines: u32,
+    pub retries: u64,
+    pub sample_rate: Option<f64>,
+    pub sample_seed: Option<u64>,
+    pub quality_scores: ScoreDistribution,
+    pub entities: EntityStats,
+    pub stages: Vec<StageResult>,
+    pub graph_stats: GraphStats,
+    // (depth, tweets) pairs of `--depth-histogram`, the last depth counts the
+    // deeper tweets too
+    pub reply_depth_histogram: Vec<(u32, u64)>,
+}
+
+impl PipelineStats {
+    pub fn write_json(&self, path: &Path) -> io::Result<()> {
+        let file = File::create(path)?;
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(())
+    }
+}
+
+// Entity density of the parsed tweets, to size the storage of an import
+#[derive(Debug, Default, Serialize)]
+pub struct EntityStats {
+    pub avg_hashtags_per_tweet: f64,
+    pub avg_mentions_per_tweet: f64,
+    pub avg_cashtags_per_tweet: f64,
+    pub tweets_with_no_entities: u64,
+}
+
+impl EntityStats {
+    pub fn from_tweets<'a>(tweets: impl Iterator<Item = &'a Tweet>) -> Self {
+        let (mut count, mut hashtags, mut mentions, mut cashtags, mut without) = (0, 0, 0, 0, 0);
+        for tweet in tweets {
+            let entities = &tweet.entities;
+            count += 1;
+            hashtags += entities.hashtags.len();
+            mentions += entities.user_mentions.len();
+            cashtags += entities.symbols.len();
+            if entities.hashtags.is_empty()
+                && entities.user_mentions.is_empty()
+                && entities.symbols.is_empty()
+            {
+                without += 1;
+            }
+        }
+        let average = |total: usize| total as f64 / count.max(1) as f64;
+        Self {
+            avg_hashtags_per_tweet: average(hashtags),
+            avg_mentions_per_tweet: average(mentions),
+            avg_cashtags_per_tweet: average(cashtags),
+            tweets_with_no_entities: without,
+        }
+    }
+}
+
+// Keeps the `capacity` items with the highest score without holding on to the
+// others, for reports over every tweet or file of a run
+pub struct TopN<T> {
+    capacity: usize,
+    // Min-heap, so that the lowest kept score is the one replaced
+    items: BinaryHeap<Reverse<Scored<T>>>,
+}
+
+struct Scored<T> {
+    score: f64,
+    item: T,
+}
+
+impl<T> PartialEq for Scored<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T> Eq for Scored<T> {}
+
+impl<T> PartialOrd for Scored<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Scored<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.score.total_cmp(&other.score)
+    }
+}
+
+impl<T> TopN<T> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            items: BinaryHeap::with_capacity(capacity + 1),
+        }
+    }
+
+    pub fn insert(&mut self, item: T, score: f64) {
+        if self.items.len() < self.capacity {
+            self.items.push(Reverse(Scored { score, item }));
+        } else if self
+            .items
+            .peek()
+            .is_some_and(|Reverse(lowest)| score > lowest.score)
+        {
+            self.items.pop();
+            self.items.push(Reverse(Scored { score, item }));
+        }
+    }
+
+    // Highest score first
+    pub fn into_sorted_vec(self) -> Vec<(T, f64)> {
+        self.items
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse(scored)| (scored.item, scored.score))
+            .collect()
+    }
+}